@@ -0,0 +1,71 @@
+use crate::peripheral::Peripheral;
+use std::ops::RangeInclusive;
+
+// Bits that always read back as 1, for NR10 (0xFF10) to NR52 (0xFF26)
+const READ_MASKS: [u8; 23] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
+
+// Sound registers and wave RAM. Nothing is played yet, but games can
+// program the APU and read back what they wrote.
+pub struct APU {
+    registers: [u8; 23],
+    wave_ram: [u8; 16],
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            registers: [0; 23],
+            wave_ram: [0; 16],
+        }
+    }
+
+    fn is_powered(&self) -> bool {
+        self.registers[0x16] & 0x80 != 0
+    }
+}
+
+impl Peripheral for APU {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[0xFF10..=0xFF3F];
+        RANGES
+    }
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF26 => {
+                let index = (addr - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize],
+            _ => 0xFF, // Unused
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => {
+                // Only the power bit is writable; turning it off clears
+                // every other register
+                self.registers[0x16] = value & 0x80;
+                if !self.is_powered() {
+                    self.registers[..0x16].fill(0);
+                }
+            }
+            0xFF10..=0xFF25 if self.is_powered() => self.registers[(addr - 0xFF10) as usize] = value,
+            0xFF30..=0xFF3F => self.wave_ram[(addr - 0xFF30) as usize] = value,
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::memory::MMU;
+
+// Everything the CPU can see through its address and data pins
+pub trait Bus {
+    fn read_byte(&mut self, addr: u16) -> u8;
+    fn write_byte(&mut self, addr: u16, value: u8);
+
+    // Read without side effects, for trace logs and debuggers
+    fn peek(&self, addr: u16) -> u8;
+
+    // An M-cycle the CPU spends on internal work without using the bus
+    fn idle(&mut self) {}
+
+    // Advance everything on the bus by `cycles` clock cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    // Interrupts that are both requested and enabled
+    fn pending_interrupts(&self) -> u8 {
+        0
+    }
+
+    // ROM bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    // Called by STOP. Switches between normal and double speed if KEY1
+    // asked for it, returning whether it did.
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+
+    // Clock cycles the CPU has to sit out because a DMA held the bus
+    fn take_stall_cycles(&mut self) -> u32 {
+        0
+    }
+}
+
+impl Bus for MMU {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        MMU::read_byte(self, addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        MMU::write_byte(self, addr, value)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        MMU::peek(self, addr)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        MMU::tick(self, cycles)
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        MMU::pending_interrupts(self)
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.cartridge().rom_bank()
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        MMU::switch_speed(self)
+    }
+
+    fn take_stall_cycles(&mut self) -> u32 {
+        MMU::take_stall_cycles(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Idle,   // recorded with address and value 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+// 64 KiB of plain RAM with nothing memory mapped, used to run the CPU in
+// isolation. IE and IF are just the bytes at 0xFFFF and 0xFF0F.
+pub struct FlatBus {
+    pub memory: Box<[u8; 0x10000]>,
+    pub cycles: u64,
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            memory: Box::new([0; 0x10000]),
+            cycles: 0,
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.memory[0xFFFF] & self.memory[0xFF0F] & 0x1F
+    }
+}
+
+// Wraps another bus and logs every M-cycle the CPU spends on it: reads,
+// writes and idle cycles
+pub struct RecordingBus<B: Bus> {
+    pub inner: B,
+    pub accesses: Vec<BusAccess>,
+}
+
+impl<B: Bus> RecordingBus<B> {
+    pub fn new(inner: B) -> Self {
+        RecordingBus {
+            inner,
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+}
+
+impl<B: Bus> Bus for RecordingBus<B> {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        let value = self.inner.read_byte(addr);
+        self.accesses.push(BusAccess { addr, value, kind: AccessKind::Read });
+        value
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.inner.write_byte(addr, value);
+        self.accesses.push(BusAccess { addr, value, kind: AccessKind::Write });
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.inner.peek(addr)
+    }
+
+    fn idle(&mut self) {
+        self.inner.idle();
+        self.accesses.push(BusAccess { addr: 0, value: 0, kind: AccessKind::Idle });
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.inner.tick(cycles)
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.inner.pending_interrupts()
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.inner.rom_bank()
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        self.inner.switch_speed()
+    }
+
+    fn take_stall_cycles(&mut self) -> u32 {
+        self.inner.take_stall_cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Registers, CPU};
+
+    fn access(kind: AccessKind, addr: u16, value: u8) -> BusAccess {
+        BusAccess { addr, value, kind }
+    }
+
+    #[test]
+    fn records_every_m_cycle_in_order() {
+        let mut flat = FlatBus::new();
+        flat.memory[..3].copy_from_slice(&[
+            0x7E,   // ld a, [hl]
+            0x03,   // inc bc
+            0x12,   // ld [de], a
+        ]);
+        flat.memory[0xC123] = 0x42;
+        let mut bus = RecordingBus::new(flat);
+        let mut cpu = CPU::new();
+        let mut registers = Registers::default();
+        registers.set_hl(0xC123);
+        registers.set_de(0xD000);
+        cpu.set_registers(registers);
+
+        cpu.step(&mut bus);
+        assert_eq!(bus.take_accesses(), [
+            access(AccessKind::Read, 0x0000, 0x7E),
+            access(AccessKind::Read, 0xC123, 0x42),
+        ]);
+        cpu.step(&mut bus);
+        assert_eq!(bus.take_accesses(), [
+            access(AccessKind::Read, 0x0001, 0x03),
+            access(AccessKind::Idle, 0x0000, 0x00),
+        ]);
+        cpu.step(&mut bus);
+        assert_eq!(bus.take_accesses(), [
+            access(AccessKind::Read, 0x0002, 0x12),
+            access(AccessKind::Write, 0xD000, 0x42),
+        ]);
+        assert_eq!(bus.inner.memory[0xD000], 0x42);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::peripheral::Peripheral;
+use std::ops::RangeInclusive;
+
+// A plain 32 KiB cartridge with no memory bank controller
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: [u8; 8192],
+}
+
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Cartridge {
+            rom,
+            ram: [0; 8192],
+        }
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // The game's name from the header at 0x134-0x143. CGB games use the last
+    // byte as a flag, and the title ends early if padded with zeros.
+    pub fn title(&self) -> String {
+        let header = self.rom.get(0x134..0x144).unwrap_or(&[]);
+        let mut title: Vec<u8> = header.iter().copied().take_while(|&byte| byte != 0).collect();
+        if title.len() == 16 && title[15] & 0x80 != 0 {
+            title.pop();
+        }
+        String::from_utf8_lossy(&title).trim().to_string()
+    }
+
+    // ROM bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> u16 {
+        1
+    }
+}
+
+impl Peripheral for Cartridge {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[0x0000..=0x7FFF, 0xA000..=0xBFFF];
+        RANGES
+    }
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.rom.get(addr as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF => self.ram[(addr - 0xA000) as usize],
+            _ => panic!("Invalid cartridge address: {:04X}", addr),
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => (), // ROM, read-only
+            0xA000..=0xBFFF => self.ram[(addr - 0xA000) as usize] = value,
+            _ => panic!("Invalid cartridge address: {:04X}", addr),
+        }
+    }
+}
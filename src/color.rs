@@ -0,0 +1,249 @@
+// Turning what the PPU produces (DMG shades 0-3, CGB RGB555 colors) into
+// pixels for the frontend
+
+// Colors for the four DMG shades as 0xRRGGBB, lightest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmgPalette(pub [u32; 4]);
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        Self::GREYSCALE
+    }
+}
+
+impl DmgPalette {
+    pub const GREYSCALE: DmgPalette = DmgPalette([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+    pub const DMG_GREEN: DmgPalette = DmgPalette([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+    pub const POCKET: DmgPalette = DmgPalette([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+    pub const LIGHT: DmgPalette = DmgPalette([0x00B581, 0x009A71, 0x00694A, 0x004F3B]);
+
+    // A preset name, or four hex colors like "e0f8d0,88c070,346856,081820"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "greyscale" | "grayscale" => return Some(Self::GREYSCALE),
+            "green" => return Some(Self::DMG_GREEN),
+            "pocket" => return Some(Self::POCKET),
+            "light" => return Some(Self::LIGHT),
+            _ => (),
+        }
+
+        let mut colors = [0; 4];
+        let mut parts = value.split(',');
+        for color in colors.iter_mut() {
+            let hex = parts.next()?.trim().trim_start_matches('#');
+            if hex.len() != 6 {
+                return None;
+            }
+            *color = u32::from_str_radix(hex, 16).ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(DmgPalette(colors))
+    }
+}
+
+// How CGB colors are adjusted for a modern display. The GBC's LCD is darker
+// and less saturated than the raw RGB555 values suggest, so games look
+// garish without this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorCorrection {
+    // Each 5-bit channel scaled to 8 bits
+    #[default]
+    Off,
+    // Channel mixing used by higan, which also caps the brightness
+    Higan,
+    // Mixing done in linear light with the panel's dimmer backlight, after
+    // the color profile common in GBC shaders. Closest to the real screen.
+    Lcd,
+}
+
+impl ColorCorrection {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(ColorCorrection::Off),
+            "higan" => Some(ColorCorrection::Higan),
+            "lcd" => Some(ColorCorrection::Lcd),
+            _ => None,
+        }
+    }
+
+    fn apply(self, color: u16) -> u32 {
+        let r = (color & 0x1F) as u32;
+        let g = (color >> 5 & 0x1F) as u32;
+        let b = (color >> 10 & 0x1F) as u32;
+        let (r, g, b) = match self {
+            ColorCorrection::Off => ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2)),
+            ColorCorrection::Higan => (
+                (r * 26 + g * 4 + b * 2).min(960) >> 2,
+                (g * 24 + b * 8).min(960) >> 2,
+                (r * 6 + g * 4 + b * 22).min(960) >> 2,
+            ),
+            ColorCorrection::Lcd => {
+                let linear = |value: u32| (value as f32 / 31.0).powf(2.2);
+                let (r, g, b) = (linear(r), linear(g), linear(b));
+                let encode = |value: f32| ((value * 0.94).clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u32;
+                (
+                    encode(0.82 * r + 0.24 * g - 0.06 * b),
+                    encode(0.125 * r + 0.665 * g + 0.21 * b),
+                    encode(0.195 * r + 0.075 * g + 0.73 * b),
+                )
+            }
+        };
+        r << 16 | g << 8 | b
+    }
+}
+
+// Layouts frontends can ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // A native-endian u32 holding 0x00RRGGBB, as minifb and most window
+    // systems take it
+    Xrgb8888,
+    // Bytes R, G, B, A in memory, for image encoders and textures
+    Rgba8888,
+    // Bytes B, G, R, A in memory
+    Bgra8888,
+    // A native-endian u16 with 5 bits red, 6 green and 5 blue
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    // Store a 0x00RRGGBB color into `out`, which is bytes_per_pixel() long
+    pub fn write(self, rgb: u32, out: &mut [u8]) {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        match self {
+            PixelFormat::Xrgb8888 => out.copy_from_slice(&rgb.to_ne_bytes()),
+            PixelFormat::Rgba8888 => out.copy_from_slice(&[r, g, b, 0xFF]),
+            PixelFormat::Bgra8888 => out.copy_from_slice(&[b, g, r, 0xFF]),
+            PixelFormat::Rgb565 => {
+                let value = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                out.copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+    }
+}
+
+pub struct ColorConverter {
+    dmg_palette: DmgPalette,
+    correction: ColorCorrection,
+    cgb_colors: Box<[u32]>,  // every RGB555 value, corrected
+}
+
+impl Default for ColorConverter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ColorConverter {
+    pub fn new() -> Self {
+        let mut converter = ColorConverter {
+            dmg_palette: DmgPalette::default(),
+            correction: ColorCorrection::default(),
+            cgb_colors: vec![0; 0x8000].into_boxed_slice(),
+        };
+        converter.set_correction(ColorCorrection::default());
+        converter
+    }
+
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.dmg_palette
+    }
+
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    pub fn correction(&self) -> ColorCorrection {
+        self.correction
+    }
+
+    pub fn set_correction(&mut self, correction: ColorCorrection) {
+        self.correction = correction;
+        for (color, pixel) in self.cgb_colors.iter_mut().enumerate() {
+            *pixel = correction.apply(color as u16);
+        }
+    }
+
+    pub fn dmg_color(&self, shade: u8) -> u32 {
+        self.dmg_palette.0[(shade & 0x03) as usize]
+    }
+
+    pub fn cgb_color(&self, color: u16) -> u32 {
+        self.cgb_colors[(color & 0x7FFF) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_presets_and_hex_palettes() {
+        assert_eq!(DmgPalette::parse("pocket"), Some(DmgPalette::POCKET));
+        assert_eq!(
+            DmgPalette::parse("e0f8d0, #88C070,346856 ,081820"),
+            Some(DmgPalette([0xE0F8D0, 0x88C070, 0x346856, 0x081820])),
+        );
+        assert_eq!(DmgPalette::parse("e0f8d0,88c070,346856"), None);
+        assert_eq!(DmgPalette::parse("e0f8d0,88c070,346856,081820,000000"), None);
+        assert_eq!(DmgPalette::parse("e0f8d0,88c070,346856,08182"), None);
+        assert_eq!(DmgPalette::parse("e0f8d0,88c070,346856,08182g"), None);
+    }
+
+    #[test]
+    fn uncorrected_colors_span_the_full_range() {
+        assert_eq!(ColorCorrection::Off.apply(0x7FFF), 0xFFFFFF);
+        assert_eq!(ColorCorrection::Off.apply(0x0000), 0x000000);
+        assert_eq!(ColorCorrection::Off.apply(0x001F), 0xFF0000);
+        assert_eq!(ColorCorrection::Off.apply(0x03E0), 0x00FF00);
+        assert_eq!(ColorCorrection::Off.apply(0x7C00), 0x0000FF);
+        assert_eq!(ColorCorrection::Off.apply(0x0010), 0x840000);
+    }
+
+    #[test]
+    fn corrected_white_is_dimmer() {
+        for correction in [ColorCorrection::Higan, ColorCorrection::Lcd] {
+            let white = correction.apply(0x7FFF);
+            let [_, channels @ ..] = white.to_be_bytes();
+            assert!(channels.iter().all(|&channel| channel < 0xFF), "{:?} gives {:06X}", correction, white);
+            assert_eq!(correction.apply(0x0000), 0x000000, "{:?}", correction);
+        }
+    }
+
+    #[test]
+    fn writes_each_pixel_format_in_its_byte_order() {
+        let rgb = 0x123456;
+        let mut out = [0; 4];
+        PixelFormat::Xrgb8888.write(rgb, &mut out);
+        assert_eq!(u32::from_ne_bytes(out), 0x00123456);
+        PixelFormat::Rgba8888.write(rgb, &mut out);
+        assert_eq!(out, [0x12, 0x34, 0x56, 0xFF]);
+        PixelFormat::Bgra8888.write(rgb, &mut out);
+        assert_eq!(out, [0x56, 0x34, 0x12, 0xFF]);
+
+        let mut out = [0; 2];
+        PixelFormat::Rgb565.write(0xFFFFFF, &mut out);
+        assert_eq!(u16::from_ne_bytes(out), 0xFFFF);
+        PixelFormat::Rgb565.write(rgb, &mut out);
+        assert_eq!(u16::from_ne_bytes(out), 0x02 << 11 | 0x0D << 5 | 0x0A);
+    }
+
+    #[test]
+    fn converter_uses_the_palette_and_correction() {
+        let mut converter = ColorConverter::new();
+        converter.set_dmg_palette(DmgPalette::DMG_GREEN);
+        assert_eq!(converter.dmg_color(3), 0x0F380F);
+        assert_eq!(converter.cgb_color(0x7FFF), 0xFFFFFF);
+        converter.set_correction(ColorCorrection::Higan);
+        assert_eq!(converter.cgb_color(0x7FFF), ColorCorrection::Higan.apply(0x7FFF));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::joypad::Button;
+
+// The palettes a CGB gives DMG-only games. Its boot ROM hashes the title in
+// the cartridge header, looks the sum up in a table of known Nintendo games
+// and loads the matching BG, OBJ0 and OBJ1 palettes. Holding a direction
+// (plus optionally A or B) while the logo shows picks one of 12 instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],   // RGB555, lightest shade first
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+const fn rgb555(colors: [u32; 4]) -> [u16; 4] {
+    let mut result = [0; 4];
+    let mut i = 0;
+    while i < 4 {
+        let (r, g, b) = (colors[i] >> 19 & 0x1F, colors[i] >> 11 & 0x1F, colors[i] >> 3 & 0x1F);
+        result[i] = (r | g << 5 | b << 10) as u16;
+        i += 1;
+    }
+    result
+}
+
+const RED: [u16; 4] = rgb555([0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000]);
+const GREEN: [u16; 4] = rgb555([0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+const BLUE: [u16; 4] = rgb555([0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]);
+const BROWN: [u16; 4] = rgb555([0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]);
+const ORANGE: [u16; 4] = rgb555([0xFFFFFF, 0xFF7300, 0x944200, 0x000000]);
+const GOLD: [u16; 4] = rgb555([0xFFC542, 0xFFD600, 0x943A00, 0x4A0000]);
+const SKY: [u16; 4] = rgb555([0xFFFFFF, 0x5ABDFF, 0xFF0000, 0x0000FF]);
+const OLIVE: [u16; 4] = rgb555([0xFFFFFF, 0xADAD84, 0x42737B, 0x000000]);
+const LAVENDER: [u16; 4] = rgb555([0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000]);
+const WHITE_BLUE: [u16; 4] = rgb555([0xFFFFFF, 0xFFFFFF, 0x63A5FF, 0x0000FF]);
+
+// A BG palette and the two others OBJs may get, indexed by the low 5 bits of
+// a palette ID
+const COMBINATIONS: [[[u16; 4]; 3]; 29] = [
+    [OLIVE, ORANGE, SKY],
+    [rgb555([0xFFFF9C, 0x94B5FF, 0x639473, 0x003A3A]), GOLD, RED],
+    [rgb555([0x6BFF00, 0xFFFFFF, 0xFF524A, 0x000000]), WHITE_BLUE, BROWN],
+    [rgb555([0x52DE00, 0xFF8400, 0xFFFF00, 0xFFFFFF]), WHITE_BLUE, RED],
+    [rgb555([0xFFFFFF, 0x7BFF00, 0xB57300, 0x000000]), RED, RED],
+    [rgb555([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]), RED, SKY],
+    [rgb555([0xFFFFFF, 0xFF9C00, 0xFF0000, 0x000000]), RED, SKY],
+    [rgb555([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]), RED, SKY],
+    [
+        rgb555([0xA59CFF, 0xFFFF00, 0x006300, 0x000000]),
+        rgb555([0xFF6352, 0xD60000, 0x630000, 0x000000]),
+        rgb555([0x0000FF, 0xFFFFFF, 0xFFFF7B, 0x0084FF]),
+    ],
+    [rgb555([0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A]), ORANGE, BLUE],
+    [
+        rgb555([0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000]),
+        rgb555([0x000000, 0xFFFFFF, 0xFF8484, 0x943A3A]),
+        rgb555([0x000000, 0xFFFFFF, 0xFF8484, 0x943A3A]),
+    ],
+    [BLUE, RED, rgb555([0xFFFFFF, 0xFFFF7B, 0x0084FF, 0xFF0000])],
+    [LAVENDER, GOLD, SKY],
+    [LAVENDER, RED, BROWN],
+    [GREEN, RED, BLUE],
+    [BROWN, GREEN, BLUE],
+    [RED, GREEN, BLUE],
+    [rgb555([0xFFFFFF, 0x00FF00, 0x318400, 0x004A00]), RED, BLUE],
+    [BROWN, BLUE, GREEN],
+    [rgb555([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]); 3],
+    [BLUE, rgb555([0xFFFF00, 0xFF0000, 0x630000, 0x000000]), GREEN],
+    [OLIVE, BROWN, GREEN],
+    [rgb555([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]); 3],
+    [rgb555([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]); 3],
+    [BLUE, RED, GREEN],
+    [rgb555([0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]), BROWN, BROWN],
+    [rgb555([0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000]), BLUE, GREEN],
+    [rgb555([0xFFFFFF, 0xFFCE00, 0x9C6300, 0x000000]); 3],
+    [rgb555([0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000]), RED, BLUE],
+];
+
+// Sum of the title bytes for each known game. From FIRST_DUPLICATE on the
+// sums collide, so the fourth letter of the title has to match as well.
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const FIRST_DUPLICATE: usize = 65;
+const TITLE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette ID for each checksum: the low 5 bits pick a combination, the top 3
+// how its palettes are handed out to BG, OBJ0 and OBJ1
+const PALETTE_IDS: [u8; 94] = [
+    0x7C, 0x08, 0x12, 0xA3, 0xA2, 0x07, 0x87, 0x4B, 0x20, 0x12, 0x65, 0xA8, 0x16, 0xA9, 0x86, 0xB1,
+    0x68, 0xA0, 0x87, 0x66, 0x12, 0xA1, 0x30, 0x3C, 0x12, 0x85, 0x12, 0x64, 0x1B, 0x07, 0x06, 0x6F,
+    0x6E, 0x6E, 0xAE, 0xAF, 0x6F, 0xB2, 0xAF, 0xB2, 0xA8, 0xAB, 0x6F, 0xAF, 0x86, 0xAE, 0xA2, 0xA2,
+    0x12, 0xAF, 0x13, 0x12, 0xA1, 0x6E, 0xAF, 0xAF, 0xAD, 0x06, 0x4C, 0x6E, 0xAF, 0xAF, 0x12, 0x7C,
+    0xAC, 0xA8, 0x6A, 0x6E, 0x13, 0xA0, 0x2D, 0xA8, 0x2B, 0xAC, 0x64, 0xAC, 0x6D, 0x87, 0xBC, 0x60,
+    0xB4, 0x13, 0x72, 0x7C, 0xB5, 0xAE, 0xAE, 0x7C, 0x7C, 0x65, 0xA2, 0x6C, 0x64, 0x85,
+];
+
+// Palette IDs for the key combos, in the order right, left, up, down, each
+// alone and then with A and with B
+const MANUAL_IDS: [[u8; 3]; 4] = [
+    [0x05, 0x7C, 0x13],
+    [0xB8, 0xAD, 0x16],
+    [0x12, 0xB0, 0x79],
+    [0x17, 0x07, 0xBA],
+];
+
+fn palette_from_id(id: u8) -> CompatPalette {
+    let [bg, a, b] = COMBINATIONS[(id & 0x1F) as usize];
+    let (obj0, obj1) = match id >> 5 {
+        0 => (bg, bg),
+        1 => (a, bg),
+        2 => (bg, a),
+        3 => (a, a),
+        4 => (bg, b),
+        _ => (a, b),
+    };
+    CompatPalette { bg, obj0, obj1 }
+}
+
+fn header_byte(rom: &[u8], addr: usize) -> u8 {
+    rom.get(addr).copied().unwrap_or(0)
+}
+
+// Sum of the 16 title bytes at 0x134-0x143
+pub fn title_checksum(rom: &[u8]) -> u8 {
+    (0x134..=0x143).fold(0u8, |sum, addr| sum.wrapping_add(header_byte(rom, addr)))
+}
+
+// Only games with Nintendo as the licensee are looked up by title
+fn is_nintendo_licensed(rom: &[u8]) -> bool {
+    match header_byte(rom, 0x14B) {
+        0x01 => true,
+        0x33 => header_byte(rom, 0x144) == b'0' && header_byte(rom, 0x145) == b'1',
+        _ => false,
+    }
+}
+
+// The palette the CGB boot ROM picks for a DMG game on its own
+pub fn rom_palette(rom: &[u8]) -> CompatPalette {
+    let mut index = 0;
+    if is_nintendo_licensed(rom) {
+        let checksum = title_checksum(rom);
+        let letter = header_byte(rom, 0x137);
+        index = TITLE_CHECKSUMS.iter().enumerate()
+            .position(|(i, &sum)| {
+                sum == checksum && (i < FIRST_DUPLICATE || TITLE_LETTERS[i - FIRST_DUPLICATE] == letter)
+            })
+            .unwrap_or(0);
+    }
+    palette_from_id(PALETTE_IDS[index])
+}
+
+// The palette chosen by holding `direction` and optionally A or B during the
+// boot animation
+pub fn manual_palette(direction: Button, button: Option<Button>) -> Option<CompatPalette> {
+    let row = match direction {
+        Button::Right => 0,
+        Button::Left => 1,
+        Button::Up => 2,
+        Button::Down => 3,
+        _ => return None,
+    };
+    let column = match button {
+        None => 0,
+        Some(Button::A) => 1,
+        Some(Button::B) => 2,
+        Some(_) => return None,
+    };
+    Some(palette_from_id(MANUAL_IDS[row][column]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A header with `title`, licensed to Nintendo unless `licensee` says
+    // otherwise
+    fn header(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn sums_the_title_bytes() {
+        assert_eq!(title_checksum(&header(b"TETRIS", 0x01)), 0xDB);
+        assert_eq!(title_checksum(&header(&[0xFF; 16], 0x01)), 0xF0);
+        assert_eq!(title_checksum(&[]), 0);
+    }
+
+    #[test]
+    fn looks_up_nintendo_titles() {
+        assert_eq!(rom_palette(&header(b"TETRIS", 0x01)), palette_from_id(0x07));
+        // New licensee code "01" is Nintendo too
+        let mut rom = header(b"TETRIS", 0x33);
+        rom[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(rom_palette(&rom), palette_from_id(0x07));
+        // Other publishers get the default palette whatever their title
+        assert_eq!(rom_palette(&header(b"TETRIS", 0x08)), palette_from_id(0x7C));
+    }
+
+    #[test]
+    fn fourth_letter_separates_colliding_checksums() {
+        // Titles summing to 0xB3 need the fourth letter to tell them apart
+        let palette = |letter: u8| {
+            let mut title = *b"ABC?";
+            title[3] = letter;
+            let mut rom = header(&title, 0x01);
+            rom[0x142] = 0xB3u8.wrapping_sub(title_checksum(&rom));
+            assert_eq!(title_checksum(&rom), 0xB3);
+            rom_palette(&rom)
+        };
+        assert_eq!(palette(b'B'), palette_from_id(0xA8));
+        assert_eq!(palette(b'U'), palette_from_id(0x60));
+        assert_eq!(palette(b'R'), palette_from_id(0x85));
+        assert_eq!(palette(b'Z'), palette_from_id(0x7C));
+    }
+
+    #[test]
+    fn palette_id_hands_out_combinations() {
+        let [bg, a, b] = COMBINATIONS[0x1C];
+        assert_eq!(palette_from_id(0x1C), CompatPalette { bg, obj0: bg, obj1: bg });
+        assert_eq!(palette_from_id(0x7C), CompatPalette { bg, obj0: a, obj1: a });
+        assert_eq!(palette_from_id(0x9C), CompatPalette { bg, obj0: bg, obj1: b });
+        assert_eq!(palette_from_id(0xBC), CompatPalette { bg, obj0: a, obj1: b });
+        assert_eq!(bg, rgb555([0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000]));
+        assert_eq!(bg[0], 0x7FFF);
+    }
+
+    #[test]
+    fn manual_combos() {
+        assert_eq!(manual_palette(Button::Right, None), Some(palette_from_id(0x05)));
+        assert_eq!(manual_palette(Button::Left, Some(Button::A)), Some(palette_from_id(0xAD)));
+        assert_eq!(manual_palette(Button::Up, Some(Button::B)), Some(palette_from_id(0x79)));
+        assert_eq!(manual_palette(Button::Down, Some(Button::B)), Some(palette_from_id(0xBA)));
+        assert_eq!(manual_palette(Button::A, None), None);
+        assert_eq!(manual_palette(Button::Up, Some(Button::Start)), None);
+    }
+}
@@ -1,4 +1,5 @@
-use crate::MMU;
+use crate::bus::Bus;
+use crate::trace::Tracer;
 
 pub struct CPU {
     // Registers
@@ -14,13 +15,18 @@ pub struct CPU {
     pc: u16,
 
     // Clock cycles
-    cycles: u32,
+    cycles: u64,
 
     // Interrupt master enable flag
     ime: bool,
 
     halt: bool,
     stop: bool,
+
+    // Set by LD B,B, which test ROMs and debuggers use as a breakpoint
+    breakpoint: bool,
+
+    tracer: Option<Tracer>,
 }
 
 // Flag register bits
@@ -29,12 +35,109 @@ const SUBTRACT_FLAG: u8 = 0b0100_0000;
 const HALF_CARRY_FLAG: u8 = 0b0010_0000;
 const CARRY_FLAG: u8 = 0b0001_0000;
 
+// Snapshot of the programmer-visible CPU state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub halt: bool,
+}
+
+impl Registers {
+    pub fn af(&self) -> u16 {
+        u16::from_le_bytes([self.f, self.a])
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_le_bytes([self.c, self.b])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_le_bytes([self.e, self.d])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_le_bytes([self.l, self.h])
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        let [f, a] = value.to_le_bytes();
+        self.a = a;
+        self.f = f & 0xF0; // Only upper 4 bits of F are used
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        [self.c, self.b] = value.to_le_bytes();
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        [self.e, self.d] = value.to_le_bytes();
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        [self.l, self.h] = value.to_le_bytes();
+    }
+
+    pub fn zero(&self) -> bool {
+        self.f & ZERO_FLAG != 0
+    }
+
+    pub fn subtract(&self) -> bool {
+        self.f & SUBTRACT_FLAG != 0
+    }
+
+    pub fn half_carry(&self) -> bool {
+        self.f & HALF_CARRY_FLAG != 0
+    }
+
+    pub fn carry(&self) -> bool {
+        self.f & CARRY_FLAG != 0
+    }
+
+    pub fn set_zero(&mut self, value: bool) {
+        self.set_flag(ZERO_FLAG, value);
+    }
+
+    pub fn set_subtract(&mut self, value: bool) {
+        self.set_flag(SUBTRACT_FLAG, value);
+    }
+
+    pub fn set_half_carry(&mut self, value: bool) {
+        self.set_flag(HALF_CARRY_FLAG, value);
+    }
+
+    pub fn set_carry(&mut self, value: bool) {
+        self.set_flag(CARRY_FLAG, value);
+    }
+
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value { self.f |= flag; } else { self.f &= !flag; }
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CPU {
     pub fn new() -> Self {
         CPU {
             a: 0, b: 0, c: 0, d: 0, e: 0, f: 0, h: 0, l: 0,
             sp: 0, pc: 0, cycles: 0, ime: false,
             halt: false, stop: false,
+            breakpoint: false,
+            tracer: None,
         }
     }
 
@@ -42,71 +145,176 @@ impl CPU {
         self.stop
     }
 
-    pub fn step(&mut self, memory: &mut MMU) -> u32 {
+    // True once after each LD B,B software breakpoint
+    pub fn take_breakpoint(&mut self) -> bool {
+        std::mem::replace(&mut self.breakpoint, false)
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a, f: self.f, b: self.b, c: self.c,
+            d: self.d, e: self.e, h: self.h, l: self.l,
+            sp: self.sp, pc: self.pc,
+            ime: self.ime, halt: self.halt,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.f = registers.f & 0xF0;
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+        self.ime = registers.ime;
+        self.halt = registers.halt;
+    }
+
+    // Total clock cycles executed since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    pub fn step(&mut self, memory: &mut impl Bus) -> u32 {
+        if self.halt {
+            // HALT ends as soon as any enabled interrupt is requested,
+            // whether or not it is serviced
+            if memory.pending_interrupts() == 0 {
+                self.cycles += 4;
+                return 4;
+            }
+            self.halt = false;
+        }
+        if self.tracer.is_some() {
+            self.trace(memory);
+        }
         let opcode = self.fetch(memory);
-        println!("Executing opcode: 0x{:02X} at PC: 0x{:04X}", opcode, self.pc);
         let cycles = self.execute(opcode, memory);
-        println!("After execution: A: 0x{:02X}, F: 0x{:02X}, BC: 0x{:04X}, DE: 0x{:04X}, HL: 0x{:04X}, SP: 0x{:04X}", 
-                 self.a, self.f, self.get_bc(), self.get_de(), self.get_hl(), self.sp);
+        self.cycles += cycles as u64;
         cycles
     }
 
-    fn fetch(&mut self, memory: &MMU) -> u8 {
+    // Service any pending interrupt, run one instruction and advance the
+    // rest of the bus by the time it took
+    pub fn advance(&mut self, memory: &mut impl Bus) -> u32 {
+        let mut cycles = 0;
+        let interrupts = memory.pending_interrupts();
+        if interrupts != 0 && self.handle_interrupts(memory, interrupts) {
+            cycles += 20;
+        }
+        cycles += self.step(memory);
+        memory.tick(cycles);
+
+        // The hardware keeps running while a DMA stalls the CPU, which can
+        // set off more DMA
+        loop {
+            let stall = memory.take_stall_cycles();
+            if stall == 0 {
+                break;
+            }
+            memory.tick(stall);
+            cycles += stall;
+        }
+        cycles
+    }
+
+    // CPU state before the next instruction, in Gameboy Doctor format
+    pub fn trace_line(&self, memory: &impl Bus) -> String {
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc,
+            memory.peek(self.pc),
+            memory.peek(self.pc.wrapping_add(1)),
+            memory.peek(self.pc.wrapping_add(2)),
+            memory.peek(self.pc.wrapping_add(3)),
+        )
+    }
+
+    fn trace(&mut self, memory: &impl Bus) {
+        let bank = match self.pc {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(memory.rom_bank()),
+            _ => None,
+        };
+        let pc = self.pc;
+        if !self.tracer.as_mut().is_some_and(|tracer| tracer.should_log(pc, bank)) {
+            return;
+        }
+        let line = self.trace_line(memory);
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(error) = tracer.write_line(&line) {
+                eprintln!("Trace log disabled: {}", error);
+                self.tracer = None;
+            }
+        }
+    }
+
+    fn fetch(&mut self, memory: &mut impl Bus) -> u8 {
         let opcode = memory.read_byte(self.pc);
         self.pc = self.pc.wrapping_add(1);
         opcode
     }
 
-    fn execute(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
+    fn execute(&mut self, opcode: u8, memory: &mut impl Bus) -> u32 {
         let cycles = match opcode {
             0x00 => self.nop(),
             0x01 => self.ld_bc_nn(memory),
             0x02 => self.ld_bc_a(memory),
-            0x03 => self.inc_bc(),
+            0x03 => self.inc_bc(memory),
             0x04 => self.inc_b(),
             0x05 => self.dec_b(),
             0x06 => self.ld_b_n(memory),
             0x07 => self.rlca(),
             0x08 => self.ld_nn_sp(memory),
-            0x09 => self.add_hl_bc(),
+            0x09 => self.add_hl_bc(memory),
             0x0A => self.ld_a_bc(memory),
-            0x0B => self.dec_bc(),
+            0x0B => self.dec_bc(memory),
             0x0C => self.inc_c(),
             0x0D => self.dec_c(),
             0x0E => self.ld_c_n(memory),
             0x0F => self.rrca(),
             
-            0x10 => self.stop(),
+            0x10 => self.stop(memory),
             0x11 => self.ld_de_nn(memory),
             0x12 => self.ld_de_a(memory),
-            0x13 => self.inc_de(),
+            0x13 => self.inc_de(memory),
             0x14 => self.inc_d(),
             0x15 => self.dec_d(),
             0x16 => self.ld_d_n(memory),
             0x17 => self.rla(),
             0x18 => self.jr_n(memory),
-            0x19 => self.add_hl_de(),
+            0x19 => self.add_hl_de(memory),
             0x1A => self.ld_a_de(memory),
-            0x1B => self.dec_de(),
+            0x1B => self.dec_de(memory),
             0x1C => self.inc_e(),
             0x1D => self.dec_e(),
             0x1E => self.ld_e_n(memory),
             0x1F => self.rra(),
             
-            0x20 => {
-                println!("Executing JR NZ,r8 at PC: 0x{:04X}, ZF: {}", self.pc, self.is_flag_set(ZERO_FLAG));
-                self.jr_nz_n(memory)},
+            0x20 => self.jr_nz_n(memory),
             0x21 => self.ld_hl_nn(memory),
             0x22 => self.ld_hl_a(memory),
-            0x23 => self.inc_hl(),
+            0x23 => self.inc_hl(memory),
             0x24 => self.inc_h(),
             0x25 => self.dec_h(),
             0x26 => self.ld_h_n(memory),
             0x27 => self.daa(),
             0x28 => self.jr_z_n(memory),
-            0x29 => self.add_hl_hl(),
+            0x29 => self.add_hl_hl(memory),
             0x2A => self.ld_a_hl_inc(memory),
-            0x2B => self.dec_hl(),
+            0x2B => self.dec_hl(memory),
             0x2C => self.inc_l(),
             0x2D => self.dec_l(),
             0x2E => self.ld_l_n(memory),
@@ -115,15 +323,15 @@ impl CPU {
             0x30 => self.jr_nc_n(memory),
             0x31 => self.ld_sp_nn(memory),
             0x32 => self.ld_hl_dec_a(memory),
-            0x33 => self.inc_sp(),
+            0x33 => self.inc_sp(memory),
             0x34 => self.inc_hl_addr(memory),
             0x35 => self.dec_hl_addr(memory),
             0x36 => self.ld_hl_n(memory),
             0x37 => self.scf(),
             0x38 => self.jr_c_n(memory),
-            0x39 => self.add_hl_sp(),
+            0x39 => self.add_hl_sp(memory),
             0x3A => self.ld_a_hl_dec(memory),
-            0x3B => self.dec_sp(),
+            0x3B => self.dec_sp(memory),
             0x3C => self.inc_a(),
             0x3D => self.dec_a(),
             0x3E => self.ld_a_n(memory),
@@ -192,7 +400,7 @@ impl CPU {
             0x79 => self.ld_a_c(),
             0x7A => self.ld_a_d(),
             0x7B => self.ld_a_e(),
-            0x7C => self.bit_7_h(),
+            0x7C => self.ld_a_h(),
             0x7D => self.ld_a_l(),
             0x7E => self.ld_a_hl(memory),
             0x7F => self.ld_a_a(),
@@ -327,7 +535,7 @@ impl CPU {
             0xF6 => self.or_n(memory),
             0xF7 => self.rst_30h(memory),
             0xF8 => self.ld_hl_sp_n(memory),
-            0xF9 => self.ld_sp_hl(),
+            0xF9 => self.ld_sp_hl(memory),
             0xFA => self.ld_a_nn(memory),
             0xFB => self.ei(),
             0xFC => panic!("Invalid opcode: 0xFC"),
@@ -337,7 +545,6 @@ impl CPU {
             
             _ => panic!("Unimplemented opcode: 0x{:02X}", opcode),
         };
-        println!("After execution: A: 0x{:02X}, F: 0x{:02X}, BC: 0x{:04X}, DE: 0x{:04X}, HL: 0x{:04X}, SP: 0x{:04X}", self.a, self.f, self.get_bc(), self.get_de(), self.get_hl(), self.sp);
         cycles
     }
 
@@ -354,22 +561,6 @@ impl CPU {
         self.f & flag != 0
     }
 
-    fn bit_7_h(&mut self) -> u32 {
-        let result = self.h & (1 << 7);
-        if result == 0
-        {
-            self.set_flag(ZERO_FLAG);
-        }
-        else
-        {
-            self.clear_flag(ZERO_FLAG);
-        }
-        
-        self.clear_flag(SUBTRACT_FLAG);
-        self.set_flag(HALF_CARRY_FLAG);
-        8
-    }
-
     // 8-bit load instructions
     fn ld_r_n(&mut self, r: u8, n: u8) -> u32 {
         match r {
@@ -406,7 +597,7 @@ impl CPU {
     }
 
     // 16-bit load instructions
-    fn ld_rr_nn(&mut self, r1: u8, r2: u8, memory: &MMU) -> u32 {
+    fn ld_rr_nn(&mut self, r1: u8, r2: u8, memory: &mut impl Bus) -> u32 {
         let low = self.fetch(memory);
         let high = self.fetch(memory);
         self.set_register(r1, high);
@@ -444,17 +635,18 @@ impl CPU {
 
     fn nop(&mut self) -> u32 { 4 }
 
-    fn ld_bc_nn(&mut self, memory: &MMU) -> u32 {
+    fn ld_bc_nn(&mut self, memory: &mut impl Bus) -> u32 {
         self.ld_rr_nn(0, 1, memory)
     }
 
-    fn ld_bc_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_bc_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.c, self.b]);
         memory.write_byte(address, self.a);
         8
     }
 
-    fn inc_bc(&mut self) -> u32 {
+    fn inc_bc(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let bc = u16::from_le_bytes([self.c, self.b]);
         let result = bc.wrapping_add(1);
         let [c, b] = result.to_le_bytes();
@@ -479,12 +671,12 @@ impl CPU {
         4
     }
 
-    fn ld_b_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_b_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(0, n)
     }
 
-    fn ld_nn_sp(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_nn_sp(&mut self, memory: &mut impl Bus) -> u32 {
         let low = self.fetch(memory);
         let high = self.fetch(memory);
         let address = u16::from_le_bytes([low, high]);
@@ -494,7 +686,8 @@ impl CPU {
         20
     }
 
-    fn add_hl_bc(&mut self) -> u32 {
+    fn add_hl_bc(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let bc = u16::from_le_bytes([self.c, self.b]);
         let (result, carry) = hl.overflowing_add(bc);
@@ -507,13 +700,14 @@ impl CPU {
         8
     }
 
-    fn ld_a_bc(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_bc(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.c, self.b]);
         self.a = memory.read_byte(address);
         8
     }
 
-    fn dec_bc(&mut self) -> u32 {
+    fn dec_bc(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let bc = u16::from_le_bytes([self.c, self.b]);
         let result = bc.wrapping_sub(1);
         let [c, b] = result.to_le_bytes();
@@ -538,22 +732,23 @@ impl CPU {
         4
     }
 
-    fn ld_c_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_c_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(1, n)
     }
 
-    fn ld_de_nn(&mut self, memory: &MMU) -> u32 {
+    fn ld_de_nn(&mut self, memory: &mut impl Bus) -> u32 {
         self.ld_rr_nn(2, 3, memory)
     }
 
-    fn ld_de_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_de_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.e, self.d]);
         memory.write_byte(address, self.a);
         8
     }
 
-    fn inc_de(&mut self) -> u32 {
+    fn inc_de(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let de = u16::from_le_bytes([self.e, self.d]);
         let result = de.wrapping_add(1);
         let [e, d] = result.to_le_bytes();
@@ -578,18 +773,20 @@ impl CPU {
         4
     }
 
-    fn ld_d_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_d_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(2, n)
     }
 
-    fn jr_n(&mut self, memory: &MMU) -> u32 {
+    fn jr_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8;
+        memory.idle();
         self.pc = self.pc.wrapping_add(n as u16);
         12
     }
 
-    fn add_hl_de(&mut self) -> u32 {
+    fn add_hl_de(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let de = u16::from_le_bytes([self.e, self.d]);
         let (result, carry) = hl.overflowing_add(de);
@@ -602,13 +799,14 @@ impl CPU {
         8
     }
 
-    fn ld_a_de(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_de(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.e, self.d]);
         self.a = memory.read_byte(address);
         8
     }
 
-    fn dec_de(&mut self) -> u32 {
+    fn dec_de(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let de = u16::from_le_bytes([self.e, self.d]);
         let result = de.wrapping_sub(1);
         let [e, d] = result.to_le_bytes();
@@ -633,14 +831,15 @@ impl CPU {
         4
     }
 
-    fn ld_e_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_e_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(3, n)
     }
 
-    fn jr_nz_n(&mut self, memory: &MMU) -> u32 {
+    fn jr_nz_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8;
         if !self.is_flag_set(ZERO_FLAG) {
+            memory.idle();
             self.pc = self.pc.wrapping_add(n as u16);
             12
         } else {
@@ -649,17 +848,18 @@ impl CPU {
         }
     }
 
-    fn ld_hl_nn(&mut self, memory: &MMU) -> u32 {
+    fn ld_hl_nn(&mut self, memory: &mut impl Bus) -> u32 {
         self.ld_rr_nn(4, 5, memory)
     }
 
-    fn ld_hl_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.a);
         8
     }
 
-    fn inc_hl(&mut self) -> u32 {
+    fn inc_hl(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let result = hl.wrapping_add(1);
         let [l, h] = result.to_le_bytes();
@@ -684,14 +884,15 @@ impl CPU {
         4
     }
 
-    fn ld_h_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_h_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(4, n)
     }
 
-    fn jr_z_n(&mut self, memory: &MMU) -> u32 {
+    fn jr_z_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8;
         if self.is_flag_set(ZERO_FLAG) {
+            memory.idle();
             self.pc = self.pc.wrapping_add(n as u16);
             12
         } else {
@@ -699,7 +900,8 @@ impl CPU {
         }
     }
 
-    fn add_hl_hl(&mut self) -> u32 {
+    fn add_hl_hl(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let (result, carry) = hl.overflowing_add(hl);
         let [l, h] = result.to_le_bytes();
@@ -711,7 +913,7 @@ impl CPU {
         8
     }
 
-    fn ld_a_hl_inc(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_hl_inc(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.a = memory.read_byte(address);
         let new_hl = address.wrapping_add(1);
@@ -721,7 +923,8 @@ impl CPU {
         8
     }
 
-    fn dec_hl(&mut self) -> u32 {
+    fn dec_hl(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let result = hl.wrapping_sub(1);
         let [l, h] = result.to_le_bytes();
@@ -746,14 +949,15 @@ impl CPU {
         4
     }
 
-    fn ld_l_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_l_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory);
         self.ld_r_n(5, n)
     }
 
-    fn jr_nc_n(&mut self, memory: &MMU) -> u32 {
+    fn jr_nc_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8;
         if !self.is_flag_set(CARRY_FLAG) {
+            memory.idle();
             self.pc = self.pc.wrapping_add(n as u16);
             12
         } else {
@@ -761,28 +965,28 @@ impl CPU {
         }
     }
 
-    fn ld_sp_nn(&mut self, memory: &MMU) -> u32 {
+    fn ld_sp_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let low = self.fetch(memory);
         let high = self.fetch(memory);
         self.sp = u16::from_le_bytes([low, high]);
         12
     }
 
-    fn ld_hl_dec_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_dec_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.get_hl();
         memory.write_byte(address, self.a);
         let new_hl = self.get_hl().wrapping_sub(1);
         self.set_hl(new_hl);
-        println!("LD (HL-),A: Wrote 0x{:02X} to address 0x{:04X}, HL is now 0x{:04X}", self.a, address, new_hl);
         8
     }
 
-    fn inc_sp(&mut self) -> u32 {
+    fn inc_sp(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         self.sp = self.sp.wrapping_add(1);
         8
     }
 
-    fn inc_hl_addr(&mut self, memory: &mut MMU) -> u32 {
+    fn inc_hl_addr(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         let result = value.wrapping_add(1);
@@ -793,7 +997,7 @@ impl CPU {
         12
     }
 
-    fn dec_hl_addr(&mut self, memory: &mut MMU) -> u32 {
+    fn dec_hl_addr(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         let result = value.wrapping_sub(1);
@@ -804,16 +1008,17 @@ impl CPU {
         12
     }
 
-    fn ld_hl_n(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_n(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = self.fetch(memory);
         memory.write_byte(address, value);
         12
     }
 
-    fn jr_c_n(&mut self, memory: &MMU) -> u32 {
+    fn jr_c_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8;
         if self.is_flag_set(CARRY_FLAG) {
+            memory.idle();
             self.pc = self.pc.wrapping_add(n as u16);
             12
         } else {
@@ -821,7 +1026,8 @@ impl CPU {
         }
     }
 
-    fn add_hl_sp(&mut self) -> u32 {
+    fn add_hl_sp(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         let hl = u16::from_le_bytes([self.l, self.h]);
         let (result, carry) = hl.overflowing_add(self.sp);
         let [l, h] = result.to_le_bytes();
@@ -833,7 +1039,7 @@ impl CPU {
         8
     }
 
-    fn ld_a_hl_dec(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_hl_dec(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.a = memory.read_byte(address);
         let new_hl = address.wrapping_sub(1);
@@ -843,7 +1049,8 @@ impl CPU {
         8
     }
 
-    fn dec_sp(&mut self) -> u32 {
+    fn dec_sp(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         self.sp = self.sp.wrapping_sub(1);
         8
     }
@@ -864,12 +1071,12 @@ impl CPU {
         4
     }
 
-    fn ld_a_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_n(&mut self, memory: &mut impl Bus) -> u32 {
         self.a = self.fetch(memory);
         8
     }
 
-    fn ld_b_b(&mut self) -> u32 { 4 }
+    fn ld_b_b(&mut self) -> u32 { self.breakpoint = true; 4 }
     fn ld_b_c(&mut self) -> u32 {
         self.ld_r1_r2(0, 1); // 0 represents register B, 1 represents register C
         4
@@ -878,7 +1085,7 @@ impl CPU {
     fn ld_b_e(&mut self) -> u32 { self.b = self.e; 4 }
     fn ld_b_h(&mut self) -> u32 { self.b = self.h; 4 }
     fn ld_b_l(&mut self) -> u32 { self.b = self.l; 4 }
-    fn ld_b_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_b_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.b = memory.read_byte(address);
         8
@@ -891,7 +1098,7 @@ impl CPU {
     fn ld_c_e(&mut self) -> u32 { self.c = self.e; 4 }
     fn ld_c_h(&mut self) -> u32 { self.c = self.h; 4 }
     fn ld_c_l(&mut self) -> u32 { self.c = self.l; 4 }
-    fn ld_c_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_c_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.c = memory.read_byte(address);
         8
@@ -907,7 +1114,7 @@ impl CPU {
     }
     fn ld_d_h(&mut self) -> u32 { self.d = self.h; 4 }
     fn ld_d_l(&mut self) -> u32 { self.d = self.l; 4 }
-    fn ld_d_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_d_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.d = memory.read_byte(address);
         8
@@ -920,7 +1127,7 @@ impl CPU {
     fn ld_e_e(&mut self) -> u32 { 4 }
     fn ld_e_h(&mut self) -> u32 { self.e = self.h; 4 }
     fn ld_e_l(&mut self) -> u32 { self.e = self.l; 4 }
-    fn ld_e_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_e_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.e = memory.read_byte(address);
         8
@@ -933,7 +1140,7 @@ impl CPU {
     fn ld_h_e(&mut self) -> u32 { self.h = self.e; 4 }
     fn ld_h_h(&mut self) -> u32 { 4 }
     fn ld_h_l(&mut self) -> u32 { self.h = self.l; 4 }
-    fn ld_h_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_h_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.h = memory.read_byte(address);
         8
@@ -946,39 +1153,39 @@ impl CPU {
     fn ld_l_e(&mut self) -> u32 { self.l = self.e; 4 }
     fn ld_l_h(&mut self) -> u32 { self.l = self.h; 4 }
     fn ld_l_l(&mut self) -> u32 { 4 }
-    fn ld_l_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_l_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.l = memory.read_byte(address);
         8
     }
     fn ld_l_a(&mut self) -> u32 { self.l = self.a; 4 }
 
-    fn ld_hl_b(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_b(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.b);
         8
     }
-    fn ld_hl_c(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_c(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.c);
         8
     }
-    fn ld_hl_d(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_d(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.d);
         8
     }
-    fn ld_hl_e(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_e(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.e);
         8
     }
-    fn ld_hl_h(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_h(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.h);
         8
     }
-    fn ld_hl_l(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_hl_l(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         memory.write_byte(address, self.l);
         8
@@ -990,7 +1197,7 @@ impl CPU {
     fn ld_a_e(&mut self) -> u32 { self.a = self.e; 4 }
     fn ld_a_h(&mut self) -> u32 { self.a = self.h; 4 }
     fn ld_a_l(&mut self) -> u32 { self.a = self.l; 4 }
-    fn ld_a_hl(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         self.a = memory.read_byte(address);
         8
@@ -1003,7 +1210,7 @@ impl CPU {
     fn add_a_e(&mut self) -> u32 { self.add_a(self.e); 4 }
     fn add_a_h(&mut self) -> u32 { self.add_a(self.h); 4 }
     fn add_a_l(&mut self) -> u32 { self.add_a(self.l); 4 }
-    fn add_a_hl(&mut self, memory: &MMU) -> u32 {
+    fn add_a_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.add_a(value);
@@ -1017,7 +1224,7 @@ impl CPU {
     fn adc_a_e(&mut self) -> u32 { self.adc_a(self.e); 4 }
     fn adc_a_h(&mut self) -> u32 { self.adc_a(self.h); 4 }
     fn adc_a_l(&mut self) -> u32 { self.adc_a(self.l); 4 }
-    fn adc_a_hl(&mut self, memory: &MMU) -> u32 {
+    fn adc_a_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.adc_a(value);
@@ -1031,7 +1238,7 @@ impl CPU {
     fn sub_e(&mut self) -> u32 { self.sub_a(self.e); 4 }
     fn sub_h(&mut self) -> u32 { self.sub_a(self.h); 4 }
     fn sub_l(&mut self) -> u32 { self.sub_a(self.l); 4 }
-    fn sub_hl(&mut self, memory: &MMU) -> u32 {
+    fn sub_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.sub_a(value);
@@ -1044,7 +1251,7 @@ impl CPU {
     fn sbc_a_e(&mut self) -> u32 { self.sbc_a(self.e); 4 }
     fn sbc_a_h(&mut self) -> u32 { self.sbc_a(self.h); 4 }
     fn sbc_a_l(&mut self) -> u32 { self.sbc_a(self.l); 4 }
-    fn sbc_a_hl(&mut self, memory: &MMU) -> u32 {
+    fn sbc_a_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.sbc_a(value);
@@ -1058,7 +1265,7 @@ impl CPU {
     fn and_e(&mut self) -> u32 { self.and_a(self.e); 4 }
     fn and_h(&mut self) -> u32 { self.and_a(self.h); 4 }
     fn and_l(&mut self) -> u32 { self.and_a(self.l); 4 }
-    fn and_hl(&mut self, memory: &MMU) -> u32 {
+    fn and_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.and_a(value);
@@ -1071,7 +1278,7 @@ impl CPU {
     fn xor_e(&mut self) -> u32 { self.xor_a(self.e); 4 }
     fn xor_h(&mut self) -> u32 { self.xor_a(self.h); 4 }
     fn xor_l(&mut self) -> u32 { self.xor_a(self.l); 4 }
-    fn xor_hl(&mut self, memory: &MMU) -> u32 {
+    fn xor_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.xor_a(value);
@@ -1084,7 +1291,7 @@ impl CPU {
     fn or_e(&mut self) -> u32 { self.or_a(self.e); 4 }
     fn or_h(&mut self) -> u32 { self.or_a(self.h); 4 }
     fn or_l(&mut self) -> u32 { self.or_a(self.l); 4 }
-    fn or_hl(&mut self, memory: &MMU) -> u32 {
+    fn or_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.or_a(value);
@@ -1097,14 +1304,15 @@ impl CPU {
     fn cp_e(&mut self) -> u32 { self.cp_a(self.e); 4 }
     fn cp_h(&mut self) -> u32 { self.cp_a(self.h); 4 }
     fn cp_l(&mut self) -> u32 { self.cp_a(self.l); 4 }
-    fn cp_hl(&mut self, memory: &MMU) -> u32 {
+    fn cp_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let address = u16::from_le_bytes([self.l, self.h]);
         let value = memory.read_byte(address);
         self.cp_a(value);
         8
     }
 
-    fn ret_nz(&mut self, memory: &MMU) -> u32 {
+    fn ret_nz(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         if !self.is_flag_set(ZERO_FLAG) {
             self.ret(memory);
             20
@@ -1113,15 +1321,16 @@ impl CPU {
         }
     }
 
-    fn pop_bc(&mut self, memory: &MMU) -> u32 {
+    fn pop_bc(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.pop_stack(memory);
         self.set_bc(value);
         12
     }
 
-    fn jp_nz_nn(&mut self, memory: &MMU) -> u32 {
+    fn jp_nz_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if !self.is_flag_set(ZERO_FLAG) {
+            memory.idle();
             self.pc = address;
             16
         } else {
@@ -1129,13 +1338,14 @@ impl CPU {
         }
     }
 
-    fn jp_nn(&mut self, memory: &MMU) -> u32 {
+    fn jp_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
+        memory.idle();
         self.pc = address;
         16
     }
 
-    fn call_nz_nn(&mut self, memory: &mut MMU) -> u32 {
+    fn call_nz_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if !self.is_flag_set(ZERO_FLAG) {
             self.push_stack(memory, self.pc);
@@ -1146,24 +1356,25 @@ impl CPU {
         }
     }
 
-    fn push_bc(&mut self, memory: &mut MMU) -> u32 {
+    fn push_bc(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.get_bc());
         16
     }
 
-    fn add_a_n(&mut self, memory: &MMU) -> u32 {
+    fn add_a_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.add_a(value);
         8
     }
 
-    fn rst_00h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_00h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0000;
         16
     }
 
-    fn ret_z(&mut self, memory: &MMU) -> u32 {
+    fn ret_z(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         if self.is_flag_set(ZERO_FLAG) {
             self.ret(memory);
             20
@@ -1172,14 +1383,16 @@ impl CPU {
         }
     }
 
-    fn ret(&mut self, memory: &MMU) -> u32 {
+    fn ret(&mut self, memory: &mut impl Bus) -> u32 {
         self.pc = self.pop_stack(memory);
+        memory.idle();
         16
     }
 
-    fn jp_z_nn(&mut self, memory: &MMU) -> u32 {
+    fn jp_z_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if self.is_flag_set(ZERO_FLAG) {
+            memory.idle();
             self.pc = address;
             16
         } else {
@@ -1187,7 +1400,7 @@ impl CPU {
         }
     }
 
-    fn call_z_nn(&mut self, memory: &mut MMU) -> u32 {
+    fn call_z_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if self.is_flag_set(ZERO_FLAG) {
             self.push_stack(memory, self.pc);
@@ -1198,26 +1411,27 @@ impl CPU {
         }
     }
 
-    fn call_nn(&mut self, memory: &mut MMU) -> u32 {
+    fn call_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         self.push_stack(memory, self.pc);
         self.pc = address;
         24
     }
 
-    fn adc_a_n(&mut self, memory: &MMU) -> u32 {
+    fn adc_a_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.adc_a(value);
         8
     }
 
-    fn rst_08h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_08h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0008;
         16
     }
 
-    fn ret_nc(&mut self, memory: &MMU) -> u32 {
+    fn ret_nc(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         if !self.is_flag_set(CARRY_FLAG) {
             self.ret(memory);
             20
@@ -1226,15 +1440,16 @@ impl CPU {
         }
     }
 
-    fn pop_de(&mut self, memory: &MMU) -> u32 {
+    fn pop_de(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.pop_stack(memory);
         self.set_de(value);
         12
     }
 
-    fn jp_nc_nn(&mut self, memory: &MMU) -> u32 {
+    fn jp_nc_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if !self.is_flag_set(CARRY_FLAG) {
+            memory.idle();
             self.pc = address;
             16
         } else {
@@ -1242,7 +1457,7 @@ impl CPU {
         }
     }
 
-    fn call_nc_nn(&mut self, memory: &mut MMU) -> u32 {
+    fn call_nc_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if !self.is_flag_set(CARRY_FLAG) {
             self.push_stack(memory, self.pc);
@@ -1253,24 +1468,25 @@ impl CPU {
         }
     }
 
-    fn push_de(&mut self, memory: &mut MMU) -> u32 {
+    fn push_de(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.get_de());
         16
     }
 
-    fn sub_n(&mut self, memory: &MMU) -> u32 {
+    fn sub_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.sub_a(value);
         8
     }
 
-    fn rst_10h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_10h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0010;
         16
     }
 
-    fn ret_c(&mut self, memory: &MMU) -> u32 {
+    fn ret_c(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         if self.is_flag_set(CARRY_FLAG) {
             self.ret(memory);
             20
@@ -1279,15 +1495,16 @@ impl CPU {
         }
     }
 
-    fn reti(&mut self, memory: &MMU) -> u32 {
+    fn reti(&mut self, memory: &mut impl Bus) -> u32 {
         self.ret(memory);
         self.ime = true;
         16
     }
 
-    fn jp_c_nn(&mut self, memory: &MMU) -> u32 {
+    fn jp_c_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if self.is_flag_set(CARRY_FLAG) {
+            memory.idle();
             self.pc = address;
             16
         } else {
@@ -1295,7 +1512,7 @@ impl CPU {
         }
     }
 
-    fn call_c_nn(&mut self, memory: &mut MMU) -> u32 {
+    fn call_c_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         if self.is_flag_set(CARRY_FLAG) {
             self.push_stack(memory, self.pc);
@@ -1306,56 +1523,58 @@ impl CPU {
         }
     }
 
-    fn sbc_a_n(&mut self, memory: &MMU) -> u32 {
+    fn sbc_a_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.sbc_a(value);
         8
     }
 
-    fn rst_18h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_18h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0018;
         16
     }
 
-    fn ldh_n_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ldh_n_a(&mut self, memory: &mut impl Bus) -> u32 {
         let offset = self.fetch(memory);
         let address = 0xFF00 | (offset as u16);
         memory.write_byte(address, self.a);
         12
     }
 
-    fn pop_hl(&mut self, memory: &MMU) -> u32 {
+    fn pop_hl(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.pop_stack(memory);
         self.set_hl(value);
         12
     }
 
-    fn ldh_c_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ldh_c_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = 0xFF00 | (self.c as u16);
         memory.write_byte(address, self.a);
         8
     }
 
-    fn push_hl(&mut self, memory: &mut MMU) -> u32 {
+    fn push_hl(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.get_hl());
         16
     }
 
-    fn and_n(&mut self, memory: &MMU) -> u32 {
+    fn and_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.and_a(value);
         8
     }
 
-    fn rst_20h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_20h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0020;
         16
     }
 
-    fn add_sp_n(&mut self, memory: &MMU) -> u32 {
+    fn add_sp_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory) as i8 as i16 as u16;
+        memory.idle();
+        memory.idle();
         let (result, carry) = self.sp.overflowing_add(value);
         self.sp = result;
 
@@ -1371,38 +1590,38 @@ impl CPU {
         4
     }
 
-    fn ld_nn_a(&mut self, memory: &mut MMU) -> u32 {
+    fn ld_nn_a(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         memory.write_byte(address, self.a);
         16
     }
 
-    fn xor_n(&mut self, memory: &MMU) -> u32 {
+    fn xor_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.xor_a(value);
         8
     }
 
-    fn rst_28h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_28h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0028;
         16
     }
 
-    fn ldh_a_n(&mut self, memory: &MMU) -> u32 {
+    fn ldh_a_n(&mut self, memory: &mut impl Bus) -> u32 {
         let offset = self.fetch(memory);
         let address = 0xFF00 | (offset as u16);
         self.a = memory.read_byte(address);
         12
     }
 
-    fn pop_af(&mut self, memory: &MMU) -> u32 {
+    fn pop_af(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.pop_stack(memory);
         self.set_af(value);
         12
     }
 
-    fn ldh_a_c(&mut self, memory: &MMU) -> u32 {
+    fn ldh_a_c(&mut self, memory: &mut impl Bus) -> u32 {
         let address = 0xFF00 | (self.c as u16);
         self.a = memory.read_byte(address);
         8
@@ -1413,25 +1632,26 @@ impl CPU {
         4
     }
 
-    fn push_af(&mut self, memory: &mut MMU) -> u32 {
+    fn push_af(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.get_af());
         16
     }
 
-    fn or_n(&mut self, memory: &MMU) -> u32 {
+    fn or_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.or_a(value);
         8
     }
 
-    fn rst_30h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_30h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0030;
         16
     }
 
-    fn ld_hl_sp_n(&mut self, memory: &MMU) -> u32 {
+    fn ld_hl_sp_n(&mut self, memory: &mut impl Bus) -> u32 {
         let n = self.fetch(memory) as i8 as i16;
+        memory.idle();
         let (result, carry) = self.sp.overflowing_add(n as u16);
         self.set_hl(result);
 
@@ -1442,12 +1662,13 @@ impl CPU {
         12
     }
 
-    fn ld_sp_hl(&mut self) -> u32 {
+    fn ld_sp_hl(&mut self, memory: &mut impl Bus) -> u32 {
+        memory.idle();
         self.sp = self.get_hl();
         8
     }
 
-    fn ld_a_nn(&mut self, memory: &MMU) -> u32 {
+    fn ld_a_nn(&mut self, memory: &mut impl Bus) -> u32 {
         let address = self.fetch_word(memory);
         self.a = memory.read_byte(address);
         16
@@ -1458,13 +1679,13 @@ impl CPU {
         4
     }
 
-    fn cp_n(&mut self, memory: &MMU) -> u32 {
+    fn cp_n(&mut self, memory: &mut impl Bus) -> u32 {
         let value = self.fetch(memory);
         self.cp_a(value);
         8
     }
 
-    fn rst_38h(&mut self, memory: &mut MMU) -> u32 {
+    fn rst_38h(&mut self, memory: &mut impl Bus) -> u32 {
         self.push_stack(memory, self.pc);
         self.pc = 0x0038;
         16
@@ -1472,20 +1693,22 @@ impl CPU {
 
     // Helper methods
 
-    fn fetch_word(&mut self, memory: &MMU) -> u16 {
+    fn fetch_word(&mut self, memory: &mut impl Bus) -> u16 {
         let low = self.fetch(memory);
         let high = self.fetch(memory);
         u16::from_le_bytes([low, high])
     }
 
-    fn push_stack(&mut self, memory: &mut MMU, value: u16) {
+    // Takes an internal cycle to decrement SP before the writes
+    fn push_stack(&mut self, memory: &mut impl Bus, value: u16) {
+        memory.idle();
         self.sp = self.sp.wrapping_sub(2);
         let [low, high] = value.to_le_bytes();
         memory.write_byte(self.sp, low);
         memory.write_byte(self.sp.wrapping_add(1), high);
     }
 
-    fn pop_stack(&mut self, memory: &MMU) -> u16 {
+    fn pop_stack(&mut self, memory: &mut impl Bus) -> u16 {
         let low = memory.read_byte(self.sp);
         let high = memory.read_byte(self.sp.wrapping_add(1));
         self.sp = self.sp.wrapping_add(2);
@@ -1606,9 +1829,8 @@ impl CPU {
 
     // CB-prefixed instructions
 
-    fn execute_cb(&mut self, memory: &mut MMU) -> u32 {
+    fn execute_cb(&mut self, memory: &mut impl Bus) -> u32 {
         let cb_opcode = self.fetch(memory);
-        println!("Executing CB-prefixed opcode: 0x{:02X} at PC: 0x{:04X}", cb_opcode, self.pc);
         match cb_opcode {
             0x00..=0x07 => self.rlc_r(cb_opcode & 0x07, memory),
             0x08..=0x0F => self.rrc_r(cb_opcode & 0x07, memory),
@@ -1624,9 +1846,9 @@ impl CPU {
         }
     }
 
-    fn rlc_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn rlc_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
-        let result = (value << 1) | (value >> 7);
+        let result = value.rotate_left(1);
         self.set_r(r, result, memory);
         self.f = 0;
         if result == 0 { self.set_flag(ZERO_FLAG); }
@@ -1634,9 +1856,9 @@ impl CPU {
         8
     }
 
-    fn rrc_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn rrc_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
-        let result = (value >> 1) | (value << 7);
+        let result = value.rotate_right(1);
         self.set_r(r, result, memory);
         self.f = 0;
         if result == 0 { self.set_flag(ZERO_FLAG); }
@@ -1644,7 +1866,7 @@ impl CPU {
         8
     }
 
-    fn rl_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn rl_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let old_carry = if self.is_flag_set(CARRY_FLAG) { 1 } else { 0 };
         let result = (value << 1) | old_carry;
@@ -1655,7 +1877,7 @@ impl CPU {
         8
     }
 
-    fn rr_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn rr_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let old_carry = if self.is_flag_set(CARRY_FLAG) { 0x80 } else { 0 };
         let result = (value >> 1) | old_carry;
@@ -1666,7 +1888,7 @@ impl CPU {
         8
     }
 
-    fn sla_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn sla_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let result = value << 1;
         self.set_r(r, result, memory);
@@ -1676,7 +1898,7 @@ impl CPU {
         8
     }
 
-    fn sra_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn sra_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let result = (value >> 1) | (value & 0x80);
         self.set_r(r, result, memory);
@@ -1686,7 +1908,7 @@ impl CPU {
         8
     }
 
-    fn swap_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn swap_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let result = ((value & 0xF) << 4) | ((value & 0xF0) >> 4);
         self.set_r(r, result, memory);
@@ -1695,7 +1917,7 @@ impl CPU {
         8
     }
 
-    fn srl_r(&mut self, r: u8, memory: &mut MMU) -> u32 {
+    fn srl_r(&mut self, r: u8, memory: &mut impl Bus) -> u32 {
         let value = self.get_r(r, memory);
         let result = value >> 1;
         self.set_r(r, result, memory);
@@ -1705,7 +1927,7 @@ impl CPU {
         8
     }
 
-    fn bit_b_r(&mut self, opcode: u8, memory: &MMU) -> u32 {
+    fn bit_b_r(&mut self, opcode: u8, memory: &mut impl Bus) -> u32 {
         let b = (opcode >> 3) & 0x07;
         let r = opcode & 0x07;
         let value = self.get_r(r, memory);
@@ -1717,7 +1939,7 @@ impl CPU {
         8
     }
 
-    fn res_b_r(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
+    fn res_b_r(&mut self, opcode: u8, memory: &mut impl Bus) -> u32 {
         let b = (opcode >> 3) & 0x07;
         let r = opcode & 0x07;
         let value = self.get_r(r, memory);
@@ -1726,7 +1948,7 @@ impl CPU {
         8
     }
 
-    fn set_b_r(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
+    fn set_b_r(&mut self, opcode: u8, memory: &mut impl Bus) -> u32 {
         let b = (opcode >> 3) & 0x07;
         let r = opcode & 0x07;
         let value = self.get_r(r, memory);
@@ -1735,7 +1957,7 @@ impl CPU {
         8
     }
 
-    fn get_r(&self, r: u8, memory: &MMU) -> u8 {
+    fn get_r(&mut self, r: u8, memory: &mut impl Bus) -> u8 {
         match r {
             0 => self.b,
             1 => self.c,
@@ -1749,7 +1971,7 @@ impl CPU {
         }
     }
 
-    fn set_r(&mut self, r: u8, value: u8, memory: &mut MMU) {
+    fn set_r(&mut self, r: u8, value: u8, memory: &mut impl Bus) {
         match r {
             0 => self.b = value,
             1 => self.c = value,
@@ -1767,7 +1989,7 @@ impl CPU {
     }
 
     // Interrupt handling
-    pub fn handle_interrupts(&mut self, memory: &mut MMU, interrupts: u8) -> bool {
+    pub fn handle_interrupts(&mut self, memory: &mut impl Bus, interrupts: u8) -> bool {
         if !self.ime {
             return false;
         }
@@ -1780,14 +2002,13 @@ impl CPU {
             return false;
         }
     
-        println!("Handling interrupts: 0x{:02X}", interrupts);
-    
         self.ime = false;
         self.halt = false;
     
         for i in 0..5 {
             if interrupts & (1 << i) != 0 {
                 memory.write_byte(0xFF0F, if_ & !(1 << i));
+                memory.idle();
                 self.push_stack(memory, self.pc);
                 self.pc = match i {
                     0 => 0x0040, // V-Blank
@@ -1797,7 +2018,7 @@ impl CPU {
                     4 => 0x0060, // Joypad
                     _ => unreachable!(),
                 };
-                println!("Servicing interrupt {}, jumping to 0x{:04X}", i, self.pc);
+                memory.idle();
                 return true;
             }
         }
@@ -1811,26 +2032,17 @@ impl CPU {
         4
     }
 
-    fn stop(&mut self) -> u32 {
-        self.stop = true;
+    fn stop(&mut self, memory: &mut impl Bus) -> u32 {
+        // STOP is always followed by a padding byte
+        self.pc = self.pc.wrapping_add(1);
+        // With a CGB speed switch armed in KEY1, STOP changes speed and
+        // execution carries on
+        if !memory.switch_speed() {
+            self.stop = true;
+        }
         4
     }
 
-    // Additional helper methods for common operations
-    fn add_hl(&mut self, value: u16) {
-        let hl = self.get_hl();
-        let (result, carry) = hl.overflowing_add(value);
-        self.set_hl(result);
-
-        self.f &= !(SUBTRACT_FLAG | CARRY_FLAG | HALF_CARRY_FLAG);
-        if carry { self.set_flag(CARRY_FLAG); }
-        if (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF { self.set_flag(HALF_CARRY_FLAG); }
-    }
-
-    fn jr(&mut self, offset: i8) {
-        self.pc = self.pc.wrapping_add(offset as u16);
-    }
-
     // Instruction implementations for remaining opcodes
     fn rlca(&mut self) -> u32 {
         let a = self.a;
@@ -1917,131 +2129,103 @@ impl CPU {
         }
         4
     }
+}
 
-    // Helper method for prefix CB instructions
-    fn prefix_cb(&mut self, memory: &mut MMU) -> u32 {
-        let opcode = self.fetch(memory);
-        match opcode {
-            0x00..=0x3F => self.rotate_shift(opcode, memory),
-            0x40..=0x7F => self.bit(opcode, memory),
-            0x80..=0xBF => self.res(opcode, memory),
-            0xC0..=0xFF => self.set(opcode, memory),
-        }
-    }
-
-    fn rotate_shift(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
-        let r = opcode & 0x07;
-        let value = self.get_r(r, memory);
-        let result = match opcode & 0xF8 {
-            0x00 => self.rlc(value),
-            0x08 => self.rrc(value),
-            0x10 => self.rl(value),
-            0x18 => self.rr(value),
-            0x20 => self.sla(value),
-            0x28 => self.sra(value),
-            0x30 => self.swap(value),
-            0x38 => self.srl(value),
-            _ => unreachable!(),
-        };
-        self.set_r(r, result, memory);
-        8
-    }
-
-    fn bit(&mut self, opcode: u8, memory: &MMU) -> u32 {
-        let b = (opcode >> 3) & 0x07;
-        let r = opcode & 0x07;
-        let value = self.get_r(r, memory);
-        self.clear_flag(SUBTRACT_FLAG);
-        self.set_flag(HALF_CARRY_FLAG);
-        if value & (1 << b) == 0 {
-            self.set_flag(ZERO_FLAG);
-        } else {
-            self.clear_flag(ZERO_FLAG);
-        }
-        8
-    }
-
-    fn res(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
-        let b = (opcode >> 3) & 0x07;
-        let r = opcode & 0x07;
-        let value = self.get_r(r, memory);
-        let result = value & !(1 << b);
-        self.set_r(r, result, memory);
-        8
-    }
-
-    fn set(&mut self, opcode: u8, memory: &mut MMU) -> u32 {
-        let b = (opcode >> 3) & 0x07;
-        let r = opcode & 0x07;
-        let value = self.get_r(r, memory);
-        let result = value | (1 << b);
-        self.set_r(r, result, memory);
-        8
-    }
-
-    // Helper methods for rotate and shift operations
-    fn rlc(&mut self, value: u8) -> u8 {
-        let result = value.rotate_left(1);
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x80 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn rrc(&mut self, value: u8) -> u8 {
-        let result = value.rotate_right(1);
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x01 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn rl(&mut self, value: u8) -> u8 {
-        let carry = self.is_flag_set(CARRY_FLAG) as u8;
-        let result = (value << 1) | carry;
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x80 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn rr(&mut self, value: u8) -> u8 {
-        let carry = (self.is_flag_set(CARRY_FLAG) as u8) << 7;
-        let result = (value >> 1) | carry;
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x01 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn sla(&mut self, value: u8) -> u8 {
-        let result = value << 1;
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x80 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn sra(&mut self, value: u8) -> u8 {
-        let result = (value >> 1) | (value & 0x80);
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x01 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-
-    fn swap(&mut self, value: u8) -> u8 {
-        let result = ((value & 0xF0) >> 4) | ((value & 0x0F) << 4);
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        result
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::memory::MMU;
+
+    #[test]
+    fn ld_a_h_copies_h_without_touching_flags() {
+        let mut mmu = MMU::new();
+        mmu.write_byte(0xC000, 0x7C);
+        let mut cpu = CPU::new();
+        cpu.pc = 0xC000;
+        cpu.h = 0x81;
+        cpu.step(&mut mmu);
+        assert_eq!(cpu.a, 0x81);
+        assert_eq!(cpu.f, 0x00);
+        assert_eq!(cpu.pc, 0xC001);
+    }
+
+    #[test]
+    fn register_pairs_round_trip() {
+        let mut registers = Registers::default();
+        registers.set_bc(0x1234);
+        registers.set_de(0x5678);
+        registers.set_hl(0x9ABC);
+        registers.set_af(0xDEF0);
+        assert_eq!((registers.b, registers.c), (0x12, 0x34));
+        assert_eq!((registers.d, registers.e), (0x56, 0x78));
+        assert_eq!((registers.h, registers.l), (0x9A, 0xBC));
+        assert_eq!((registers.a, registers.f), (0xDE, 0xF0));
+        assert_eq!((registers.bc(), registers.de(), registers.hl(), registers.af()), (0x1234, 0x5678, 0x9ABC, 0xDEF0));
+    }
+
+    #[test]
+    fn low_nibble_of_f_always_reads_zero() {
+        let mut registers = Registers::default();
+        registers.set_af(0x12FF);
+        assert_eq!(registers.af(), 0x12F0);
+
+        let mut cpu = CPU::new();
+        cpu.set_registers(Registers { f: 0xFF, pc: 0x0150, sp: 0xFFFE, ime: true, ..Registers::default() });
+        let registers = cpu.registers();
+        assert_eq!(registers.f, 0xF0);
+        assert_eq!((registers.pc, registers.sp, registers.ime, registers.halt), (0x0150, 0xFFFE, true, false));
+    }
+
+    #[test]
+    fn flag_accessors_map_to_the_bits_of_f() {
+        let mut registers = Registers::default();
+        registers.set_zero(true);
+        assert_eq!(registers.f, 0x80);
+        registers.set_subtract(true);
+        assert_eq!(registers.f, 0xC0);
+        registers.set_half_carry(true);
+        assert_eq!(registers.f, 0xE0);
+        registers.set_carry(true);
+        assert_eq!(registers.f, 0xF0);
+        registers.set_zero(false);
+        registers.set_half_carry(false);
+        assert_eq!(registers.f, 0x50);
+        assert!(!registers.zero() && registers.subtract() && !registers.half_carry() && registers.carry());
+    }
+
+    #[test]
+    fn stop_skips_its_padding_byte() {
+        let mut bus = FlatBus::new();
+        bus.memory[..3].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let mut cpu = CPU::new();
+        cpu.step(&mut bus);
+        assert!(cpu.is_stopped());
+        assert_eq!(cpu.registers().pc, 0x0002);
+    }
+
+    #[test]
+    fn stop_switches_speed_when_armed() {
+        let mut mmu = MMU::new();
+        mmu.set_cgb_mode(true);
+        mmu.write_byte(0xC000, 0x10);
+        mmu.write_byte(0xC001, 0x00);
+        mmu.write_byte(0xC002, 0x10);
+        mmu.write_byte(0xC003, 0x00);
+        let mut cpu = CPU::new();
+        cpu.set_registers(Registers { pc: 0xC000, ..Registers::default() });
+
+        mmu.write_byte(0xFF4D, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7F);
+        cpu.step(&mut mmu);
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.registers().pc, 0xC002);
+        assert!(mmu.is_double_speed());
+        assert_eq!(mmu.read_byte(0xFF4D), 0xFE);
+
+        // Without the switch armed, STOP stops
+        cpu.step(&mut mmu);
+        assert!(cpu.is_stopped());
+        assert!(mmu.is_double_speed());
     }
-
-    fn srl(&mut self, value: u8) -> u8 {
-        let result = value >> 1;
-        self.f = 0;
-        if result == 0 { self.set_flag(ZERO_FLAG); }
-        if value & 0x01 != 0 { self.set_flag(CARRY_FLAG); }
-        result
-    }
-}
\ No newline at end of file
+}
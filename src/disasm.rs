@@ -0,0 +1,409 @@
+use crate::memory::MMU;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Anything the disassembler can pull instruction bytes from
+pub trait ByteSource {
+    fn byte_at(&self, addr: u16) -> u8;
+
+    // ROM bank currently mapped at 0x4000-0x7FFF, used to resolve symbols
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+}
+
+impl ByteSource for MMU {
+    fn byte_at(&self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+}
+
+// A raw ROM image viewed through the CPU address space with a fixed bank
+// mapped at 0x4000-0x7FFF
+pub struct RomView<'a> {
+    rom: &'a [u8],
+    bank: u16,
+}
+
+impl<'a> RomView<'a> {
+    pub fn new(rom: &'a [u8], bank: u16) -> Self {
+        RomView { rom, bank }
+    }
+
+    // Offset into the ROM file for a CPU address, if it is backed by ROM
+    pub fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => self.bank as usize * 0x4000 + (addr as usize - 0x4000),
+            _ => return None,
+        };
+        if offset < self.rom.len() { Some(offset) } else { None }
+    }
+}
+
+impl ByteSource for RomView<'_> {
+    fn byte_at(&self, addr: u16) -> u8 {
+        self.offset(addr).map_or(0xFF, |offset| self.rom[offset])
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.bank
+    }
+}
+
+impl ByteSource for [u8] {
+    fn byte_at(&self, addr: u16) -> u8 {
+        self.get(addr as usize).copied().unwrap_or(0xFF)
+    }
+}
+
+// Labels keyed by bank and address, as found in RGBDS .sym files
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: HashMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable { symbols: HashMap::new() }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    // Parse "BB:AAAA Name" lines, ignoring comments and anything malformed
+    pub fn parse(text: &str) -> Self {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            let (Some(location), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let Some((bank, addr)) = location.split_once(':') else {
+                continue;
+            };
+            if let (Ok(bank), Ok(addr)) = (u16::from_str_radix(bank, 16), u16::from_str_radix(addr, 16)) {
+                table.insert(bank, addr, name);
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, bank: u16, addr: u16, name: &str) {
+        self.symbols.insert((Self::key_bank(bank, addr), addr), name.to_string());
+    }
+
+    pub fn lookup(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.symbols.get(&(Self::key_bank(bank, addr), addr)).map(String::as_str)
+    }
+
+    // Iterate over (bank, address, name) entries
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16, &str)> {
+        self.symbols.iter().map(|(&(bank, addr), name)| (bank, addr, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // Only the switchable ROM area is banked as far as symbols are concerned
+    fn key_bank(bank: u16, addr: u16) -> u16 {
+        if (0x4000..=0x7FFF).contains(&addr) { bank } else { 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(&'static str),
+    Condition(&'static str),
+    Indirect(&'static str),  // [hl], [bc], [hl+], [c] ...
+    Imm8(u8),
+    Imm16(u16),
+    Address(u16),            // [a16]
+    HighAddress(u8),         // [$FF00+a8]
+    Target(u16),             // jp/jr/call destination, already made absolute
+    SpOffset(i8),            // sp+e8
+    Signed(i8),
+    Bit(u8),
+    Vector(u8),              // rst target
+}
+
+// How an instruction affects the flow of execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Jump(u16),      // unconditional jp/jr
+    Branch(u16),    // conditional jp/jr, falls through otherwise
+    Call(u16),      // call/rst, conditional or not, returns afterwards
+    Return,         // unconditional ret/reti
+    Indirect,       // jp hl
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bank: u16, // ROM bank mapped at 0x4000-0x7FFF when decoded
+    pub bytes: [u8; 3],
+    pub length: u8,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    pub flow: Flow,
+}
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const RP: [&str; 4] = ["bc", "de", "hl", "sp"];
+const RP2: [&str; 4] = ["bc", "de", "hl", "af"];
+const CC: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+fn r8(index: u8) -> Operand {
+    if index == 6 {
+        Operand::Indirect("[hl]")
+    } else {
+        Operand::Register(R8[index as usize])
+    }
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length as u16)
+    }
+
+    pub fn is_cb_prefixed(&self) -> bool {
+        self.bytes[0] == 0xCB
+    }
+
+    // Render the instruction, replacing addresses with labels where known
+    pub fn format(&self, symbols: Option<&SymbolTable>) -> String {
+        if self.operands.is_empty() {
+            return self.mnemonic.to_string();
+        }
+        let operands: Vec<String> = self.operands.iter()
+            .map(|operand| self.format_operand(*operand, symbols))
+            .collect();
+        format!("{} {}", self.mnemonic, operands.join(", "))
+    }
+
+    fn format_operand(&self, operand: Operand, symbols: Option<&SymbolTable>) -> String {
+        let label = |addr: u16| {
+            symbols.and_then(|table| table.lookup(self.bank, addr)).map(str::to_string)
+        };
+        match operand {
+            Operand::Register(name) | Operand::Condition(name) | Operand::Indirect(name) => name.to_string(),
+            Operand::Imm8(value) => format!("${:02X}", value),
+            Operand::Imm16(value) => format!("${:04X}", value),
+            Operand::Address(addr) => format!("[{}]", label(addr).unwrap_or_else(|| format!("${:04X}", addr))),
+            Operand::HighAddress(offset) => {
+                let addr = 0xFF00 | offset as u16;
+                format!("[{}]", label(addr).unwrap_or_else(|| format!("${:04X}", addr)))
+            }
+            Operand::Target(addr) => label(addr).unwrap_or_else(|| format!("${:04X}", addr)),
+            Operand::SpOffset(offset) if offset < 0 => format!("sp - {}", -(offset as i16)),
+            Operand::SpOffset(offset) => format!("sp + {}", offset),
+            Operand::Signed(value) => format!("{}", value),
+            Operand::Bit(bit) => format!("{}", bit),
+            Operand::Vector(vector) => format!("${:02X}", vector),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(None))
+    }
+}
+
+// Decode the instruction at `address`
+pub fn decode<S: ByteSource + ?Sized>(source: &S, address: u16) -> Instruction {
+    let opcode = source.byte_at(address);
+    let byte1 = source.byte_at(address.wrapping_add(1));
+    let byte2 = source.byte_at(address.wrapping_add(2));
+    let imm16 = u16::from_le_bytes([byte1, byte2]);
+    let relative = address.wrapping_add(2).wrapping_add(byte1 as i8 as u16);
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+
+    use Operand::*;
+    let (mnemonic, operands, length, flow): (&'static str, Vec<Operand>, u8, Flow) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("nop", vec![], 1, Flow::Continue),
+            1 => ("ld", vec![Address(imm16), Register("sp")], 3, Flow::Continue),
+            2 => ("stop", vec![], 2, Flow::Continue),
+            3 => ("jr", vec![Target(relative)], 2, Flow::Jump(relative)),
+            _ => ("jr", vec![Condition(CC[y as usize - 4]), Target(relative)], 2, Flow::Branch(relative)),
+        },
+        (0, 1) if q == 0 => ("ld", vec![Register(RP[p as usize]), Imm16(imm16)], 3, Flow::Continue),
+        (0, 1) => ("add", vec![Register("hl"), Register(RP[p as usize])], 1, Flow::Continue),
+        (0, 2) => {
+            let pointer = Indirect(["[bc]", "[de]", "[hl+]", "[hl-]"][p as usize]);
+            let operands = if q == 0 { vec![pointer, Register("a")] } else { vec![Register("a"), pointer] };
+            ("ld", operands, 1, Flow::Continue)
+        }
+        (0, 3) => (if q == 0 { "inc" } else { "dec" }, vec![Register(RP[p as usize])], 1, Flow::Continue),
+        (0, 4) => ("inc", vec![r8(y)], 1, Flow::Continue),
+        (0, 5) => ("dec", vec![r8(y)], 1, Flow::Continue),
+        (0, 6) => ("ld", vec![r8(y), Imm8(byte1)], 2, Flow::Continue),
+        (0, 7) => (ACC[y as usize], vec![], 1, Flow::Continue),
+        (1, _) if y == 6 && z == 6 => ("halt", vec![], 1, Flow::Continue),
+        (1, _) => ("ld", vec![r8(y), r8(z)], 1, Flow::Continue),
+        (2, _) => (ALU[y as usize], vec![Register("a"), r8(z)], 1, Flow::Continue),
+        (3, 0) => match y {
+            0..=3 => ("ret", vec![Condition(CC[y as usize])], 1, Flow::Continue),
+            4 => ("ldh", vec![HighAddress(byte1), Register("a")], 2, Flow::Continue),
+            5 => ("add", vec![Register("sp"), Signed(byte1 as i8)], 2, Flow::Continue),
+            6 => ("ldh", vec![Register("a"), HighAddress(byte1)], 2, Flow::Continue),
+            _ => ("ld", vec![Register("hl"), SpOffset(byte1 as i8)], 2, Flow::Continue),
+        },
+        (3, 1) if q == 0 => ("pop", vec![Register(RP2[p as usize])], 1, Flow::Continue),
+        (3, 1) => match p {
+            0 => ("ret", vec![], 1, Flow::Return),
+            1 => ("reti", vec![], 1, Flow::Return),
+            2 => ("jp", vec![Register("hl")], 1, Flow::Indirect),
+            _ => ("ld", vec![Register("sp"), Register("hl")], 1, Flow::Continue),
+        },
+        (3, 2) => match y {
+            0..=3 => ("jp", vec![Condition(CC[y as usize]), Target(imm16)], 3, Flow::Branch(imm16)),
+            4 => ("ldh", vec![Indirect("[c]"), Register("a")], 1, Flow::Continue),
+            5 => ("ld", vec![Address(imm16), Register("a")], 3, Flow::Continue),
+            6 => ("ldh", vec![Register("a"), Indirect("[c]")], 1, Flow::Continue),
+            _ => ("ld", vec![Register("a"), Address(imm16)], 3, Flow::Continue),
+        },
+        (3, 3) => match y {
+            0 => ("jp", vec![Target(imm16)], 3, Flow::Jump(imm16)),
+            1 => decode_cb(byte1),
+            6 => ("di", vec![], 1, Flow::Continue),
+            7 => ("ei", vec![], 1, Flow::Continue),
+            _ => ("db", vec![Imm8(opcode)], 1, Flow::Invalid),
+        },
+        (3, 4) if y < 4 => ("call", vec![Condition(CC[y as usize]), Target(imm16)], 3, Flow::Call(imm16)),
+        (3, 5) if q == 0 => ("push", vec![Register(RP2[p as usize])], 1, Flow::Continue),
+        (3, 5) if p == 0 => ("call", vec![Target(imm16)], 3, Flow::Call(imm16)),
+        (3, 6) => (ALU[y as usize], vec![Register("a"), Imm8(byte1)], 2, Flow::Continue),
+        (3, 7) => ("rst", vec![Vector(y * 8)], 1, Flow::Call(y as u16 * 8)),
+        _ => ("db", vec![Imm8(opcode)], 1, Flow::Invalid),
+    };
+
+    Instruction {
+        address,
+        bank: source.rom_bank(),
+        bytes: [opcode, byte1, byte2],
+        length,
+        mnemonic,
+        operands,
+        flow,
+    }
+}
+
+fn decode_cb(opcode: u8) -> (&'static str, Vec<Operand>, u8, Flow) {
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    match opcode >> 6 {
+        0 => (ROT[y as usize], vec![r8(z)], 2, Flow::Continue),
+        1 => ("bit", vec![Operand::Bit(y), r8(z)], 2, Flow::Continue),
+        2 => ("res", vec![Operand::Bit(y), r8(z)], 2, Flow::Continue),
+        _ => ("set", vec![Operand::Bit(y), r8(z)], 2, Flow::Continue),
+    }
+}
+
+// Decode `count` consecutive instructions starting at `address`
+pub fn disassemble<S: ByteSource + ?Sized>(source: &S, address: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut pc = address;
+    for _ in 0..count {
+        let instruction = decode(source, pc);
+        pc = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8], address: u16) -> Instruction {
+        let mut memory = vec![0; address as usize];
+        memory.extend_from_slice(bytes);
+        decode(memory.as_slice(), address)
+    }
+
+    #[test]
+    fn decodes_unprefixed_instructions() {
+        let nop = decode_bytes(&[0x00], 0);
+        assert_eq!((nop.to_string(), nop.length), ("nop".to_string(), 1));
+        assert_eq!(decode_bytes(&[0x7C], 0).to_string(), "ld a, h");
+        assert_eq!(decode_bytes(&[0x77], 0).to_string(), "ld [hl], a");
+        assert_eq!(decode_bytes(&[0x2A], 0).to_string(), "ld a, [hl+]");
+        assert_eq!(decode_bytes(&[0xAF], 0).to_string(), "xor a, a");
+        assert_eq!(decode_bytes(&[0xFF], 0).flow, Flow::Call(0x38));
+        assert_eq!(decode_bytes(&[0xD3], 0).flow, Flow::Invalid);
+    }
+
+    #[test]
+    fn decodes_cb_prefixed_instructions() {
+        let bit = decode_bytes(&[0xCB, 0x7C], 0);
+        assert!(bit.is_cb_prefixed());
+        assert_eq!((bit.to_string(), bit.length), ("bit 7, h".to_string(), 2));
+        assert_eq!(decode_bytes(&[0xCB, 0x37], 0).to_string(), "swap a");
+        assert_eq!(decode_bytes(&[0xCB, 0x86], 0).to_string(), "res 0, [hl]");
+        assert_eq!(decode_bytes(&[0xCB, 0xFF], 0).to_string(), "set 7, a");
+    }
+
+    #[test]
+    fn decodes_immediates() {
+        assert_eq!(decode_bytes(&[0x3E, 0x42], 0).to_string(), "ld a, $42");
+        let load = decode_bytes(&[0x21, 0x34, 0x12], 0);
+        assert_eq!((load.to_string(), load.length), ("ld hl, $1234".to_string(), 3));
+        assert_eq!(decode_bytes(&[0xEA, 0x00, 0xC0], 0).to_string(), "ld [$C000], a");
+        assert_eq!(decode_bytes(&[0xE0, 0x40], 0).to_string(), "ldh [$FF40], a");
+        assert_eq!(decode_bytes(&[0xF8, 0xFE], 0).to_string(), "ld hl, sp - 2");
+        assert_eq!(decode_bytes(&[0xE8, 0x05], 0).to_string(), "add sp, 5");
+    }
+
+    #[test]
+    fn resolves_relative_targets() {
+        // Offsets count from the end of the two-byte instruction
+        let back = decode_bytes(&[0x18, 0xFE], 0x0150);
+        assert_eq!((back.to_string(), back.flow), ("jr $0150".to_string(), Flow::Jump(0x0150)));
+        let forward = decode_bytes(&[0x20, 0x10], 0x0150);
+        assert_eq!(forward.to_string(), "jr nz, $0162");
+        assert_eq!(forward.flow, Flow::Branch(0x0162));
+    }
+
+    #[test]
+    fn substitutes_symbols() {
+        let symbols = SymbolTable::parse("00:0150 Main ; entry\n02:4000 BankedRoutine\n00:FF40 rLCDC\nnot a symbol\n");
+        assert_eq!(symbols.len(), 3);
+
+        let call = decode_bytes(&[0xCD, 0x50, 0x01], 0);
+        assert_eq!(call.format(Some(&symbols)), "call Main");
+        assert_eq!(decode_bytes(&[0xE0, 0x40], 0).format(Some(&symbols)), "ldh [rLCDC], a");
+
+        // Banked labels only match when their bank is mapped
+        let mut rom = vec![0; 0x8000];
+        rom[..3].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        let jump = decode(&RomView::new(&rom, 2), 0);
+        assert_eq!(jump.format(Some(&symbols)), "jp BankedRoutine");
+        let jump = decode(&RomView::new(&rom, 1), 0);
+        assert_eq!(jump.format(Some(&symbols)), "jp $4000");
+    }
+}
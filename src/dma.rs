@@ -0,0 +1,136 @@
+// OAM DMA (0xFF46). Writing a page number copies 160 bytes from
+// 0xXX00-0xXX9F into OAM, one byte per M-cycle, after a one M-cycle setup.
+// The MMU does the copying since the source can be anywhere on the bus.
+pub struct OamDma {
+    register: u8,                   // last value written to 0xFF46
+    running: Option<(u8, u8)>,      // source page and next OAM index
+    pending: Option<(u8, u8)>,      // requested page and M-cycles of setup left
+}
+
+pub const OAM_SIZE: u8 = 160;
+
+impl Default for OamDma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        OamDma {
+            register: 0xFF,
+            running: None,
+            pending: None,
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        self.register
+    }
+
+    // Request a transfer. One already running keeps going until the new one
+    // has finished its setup and takes over.
+    pub fn write_byte(&mut self, value: u8) {
+        self.register = value;
+        self.pending = Some((value, 1));
+    }
+
+    // True while bytes are being copied and the CPU is locked out of the bus
+    pub fn is_active(&self) -> bool {
+        self.running.is_some()
+    }
+
+    // Advance one M-cycle, returning the source address and OAM index of
+    // the byte to copy in it
+    pub fn step(&mut self) -> Option<(u16, u8)> {
+        let transfer = self.running.map(|(page, index)| (u16::from_be_bytes([page, index]), index));
+        if let Some((page, index)) = self.running {
+            self.running = if index + 1 < OAM_SIZE { Some((page, index + 1)) } else { None };
+        }
+
+        if let Some((page, setup)) = self.pending {
+            if setup > 1 {
+                self.pending = Some((page, setup - 1));
+            } else {
+                self.pending = None;
+                self.running = Some((page, 0));
+            }
+        }
+        transfer
+    }
+}
+
+// CGB VRAM DMA (0xFF51-0xFF55). Copies 16-byte blocks into VRAM, either all
+// at once while the CPU waits (general-purpose DMA) or one block at the start
+// of every HBlank. Like OAM DMA, the MMU moves the bytes.
+pub struct Hdma {
+    source: u16,
+    destination: u16,   // offset into VRAM
+    blocks: u8,         // 16-byte blocks left to copy
+    hblank: bool,       // copying one block per HBlank
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            blocks: 0,
+            hblank: false,
+        }
+    }
+
+    // HDMA5: bit 7 clear while an HBlank transfer is running, and the
+    // blocks left minus one. 0xFF once done.
+    pub fn read_byte(&self) -> u8 {
+        let length = self.blocks.wrapping_sub(1) & 0x7F;
+        if self.hblank { length } else { 0x80 | length }
+    }
+
+    // HDMA1-4 set the addresses, HDMA5 starts a transfer. Returns true when
+    // a general-purpose transfer was requested and should run right away.
+    pub fn write_byte(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0xFF51 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 if self.hblank && value & 0x80 == 0 => {
+                // Cancel, leaving the remaining length readable
+                self.hblank = false;
+            }
+            0xFF55 => {
+                self.blocks = (value & 0x7F) + 1;
+                self.hblank = value & 0x80 != 0;
+                return !self.hblank;
+            }
+            _ => panic!("Invalid HDMA register address: {:04X}", addr),
+        }
+        false
+    }
+
+    pub fn is_hblank_active(&self) -> bool {
+        self.hblank
+    }
+
+    // Source address and VRAM offset of the next block, moving past it
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.blocks == 0 {
+            return None;
+        }
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(16);
+        self.destination = (self.destination + 16) & 0x1FF0;
+        self.blocks -= 1;
+        if self.blocks == 0 {
+            self.hblank = false;
+        }
+        Some(block)
+    }
+}
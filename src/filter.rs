@@ -0,0 +1,666 @@
+// Post-processing applied to finished frames on the CPU, after conversion to
+// 0x00RRGGBB. The same filter output feeds the window, screenshots and
+// recordings.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    // Every pixel repeated into an n x n block
+    Nearest(usize),
+    // EPX/AdvMAME edge-directed scalers, which round off staircase edges
+    // without adding new colors
+    Scale2x,
+    Scale3x,
+    // hq2x: blends each pixel with the neighbours that differ from it in YUV,
+    // choosing the blend from the 256 possible patterns of differences
+    Hq2x,
+    // n x n blocks with darker gaps between them, like the DMG dot matrix
+    LcdGrid(usize),
+}
+
+// In the order the frontend cycles through them
+pub const FILTERS: [Filter; 6] = [
+    Filter::None,
+    Filter::Nearest(3),
+    Filter::Scale2x,
+    Filter::Scale3x,
+    Filter::Hq2x,
+    Filter::LcdGrid(3),
+];
+
+impl Filter {
+    // "none", "scale2x", "scale3x", "hq2x", or "nearest" and "lcd" with an
+    // optional scale like "nearest:4"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (name, scale) = match value.split_once(':') {
+            Some((name, scale)) => (name, Some(scale.parse().ok().filter(|scale| (1..=8).contains(scale))?)),
+            None => (value, None),
+        };
+        match (name, scale) {
+            ("none", None) => Some(Filter::None),
+            ("nearest", scale) => Some(Filter::Nearest(scale.unwrap_or(3))),
+            ("scale2x", None) => Some(Filter::Scale2x),
+            ("scale3x", None) => Some(Filter::Scale3x),
+            ("hq2x", None) => Some(Filter::Hq2x),
+            ("lcd", scale) => Some(Filter::LcdGrid(scale.unwrap_or(3).max(2))),
+            _ => None,
+        }
+    }
+
+    pub fn scale(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Nearest(scale) | Filter::LcdGrid(scale) => scale,
+            Filter::Scale2x | Filter::Hq2x => 2,
+            Filter::Scale3x => 3,
+        }
+    }
+}
+
+pub struct VideoFilter {
+    filter: Filter,
+    ghosting: bool,
+    previous: Vec<u32>,  // last input frame, for ghosting
+    blended: Vec<u32>,
+    output: Vec<u32>,
+}
+
+impl Default for VideoFilter {
+    fn default() -> Self {
+        Self::new(Filter::None)
+    }
+}
+
+impl VideoFilter {
+    pub fn new(filter: Filter) -> Self {
+        VideoFilter {
+            filter,
+            ghosting: false,
+            previous: Vec::new(),
+            blended: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn ghosting(&self) -> bool {
+        self.ghosting
+    }
+
+    // Average each frame with the one before, like the slow DMG LCD that
+    // makes flickering sprites look transparent
+    pub fn set_ghosting(&mut self, enabled: bool) {
+        self.ghosting = enabled;
+        self.previous.clear();
+    }
+
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width * self.filter.scale(), height * self.filter.scale())
+    }
+
+    // Filter a width x height frame. The result is output_size() pixels and
+    // stays valid until the next call.
+    pub fn apply(&mut self, frame: &[u32], width: usize, height: usize) -> &[u32] {
+        assert_eq!(frame.len(), width * height, "frame has the wrong size");
+
+        let mut blended = std::mem::take(&mut self.blended);
+        let input = if self.ghosting && self.previous.len() == frame.len() {
+            blended.clear();
+            blended.extend(frame.iter().zip(&self.previous).map(|(&a, &b)| average(a, b)));
+            &blended
+        } else {
+            frame
+        };
+        if self.ghosting {
+            self.previous.clear();
+            self.previous.extend_from_slice(frame);
+        }
+
+        let (out_width, out_height) = self.output_size(width, height);
+        self.output.resize(out_width * out_height, 0);
+        let source = Source { pixels: input, width, height };
+        match self.filter {
+            Filter::None => self.output.copy_from_slice(input),
+            Filter::Nearest(scale) => nearest(&source, scale, &mut self.output),
+            Filter::Scale2x => scale2x(&source, &mut self.output),
+            Filter::Scale3x => scale3x(&source, &mut self.output),
+            Filter::Hq2x => hq2x(&source, &mut self.output),
+            Filter::LcdGrid(scale) => lcd_grid(&source, scale, &mut self.output),
+        }
+        self.blended = blended;
+        &self.output
+    }
+}
+
+struct Source<'a> {
+    pixels: &'a [u32],
+    width: usize,
+    height: usize,
+}
+
+impl Source<'_> {
+    // The pixel at (x + dx, y + dy), clamped to the edges
+    fn at(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        let x = x.saturating_add_signed(dx).min(self.width - 1);
+        let y = y.saturating_add_signed(dy).min(self.height - 1);
+        self.pixels[y * self.width + x]
+    }
+}
+
+fn average(a: u32, b: u32) -> u32 {
+    (a & b) + (((a ^ b) & 0xFEFEFE) >> 1)
+}
+
+// Weighted mix of colors whose weights add up to 1 << shift
+fn interpolate(colors: &[(u32, u32)], shift: u32) -> u32 {
+    let (mut r, mut g, mut b) = (0, 0, 0);
+    for &(color, weight) in colors {
+        r += (color >> 16 & 0xFF) * weight;
+        g += (color >> 8 & 0xFF) * weight;
+        b += (color & 0xFF) * weight;
+    }
+    (r >> shift) << 16 | (g >> shift) << 8 | b >> shift
+}
+
+fn nearest(source: &Source, scale: usize, out: &mut [u32]) {
+    let out_width = source.width * scale;
+    for (y, row) in out.chunks_exact_mut(out_width).enumerate() {
+        let line = &source.pixels[y / scale * source.width..][..source.width];
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = line[x / scale];
+        }
+    }
+}
+
+fn lcd_grid(source: &Source, scale: usize, out: &mut [u32]) {
+    let out_width = source.width * scale;
+    for (y, row) in out.chunks_exact_mut(out_width).enumerate() {
+        let line = &source.pixels[y / scale * source.width..][..source.width];
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let color = line[x / scale];
+            let gap = x % scale == scale - 1 || y % scale == scale - 1;
+            *pixel = if gap { interpolate(&[(color, 5)], 3) } else { color };
+        }
+    }
+}
+
+// Write a scale x scale block of output pixels for source pixel (x, y)
+fn put_block(out: &mut [u32], out_width: usize, x: usize, y: usize, block: &[u32]) {
+    let scale = if block.len() == 4 { 2 } else { 3 };
+    for (row, pixels) in block.chunks_exact(scale).enumerate() {
+        out[(y * scale + row) * out_width + x * scale..][..scale].copy_from_slice(pixels);
+    }
+}
+
+fn scale2x(source: &Source, out: &mut [u32]) {
+    let out_width = source.width * 2;
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let p = source.at(x, y, 0, 0);
+            let a = source.at(x, y, 0, -1);
+            let b = source.at(x, y, 1, 0);
+            let c = source.at(x, y, -1, 0);
+            let d = source.at(x, y, 0, 1);
+            let block = if c == a && c != d && a != b { a } else { p };
+            let block = [
+                block,
+                if a == b && a != c && b != d { b } else { p },
+                if d == c && d != b && c != a { c } else { p },
+                if b == d && b != a && d != c { d } else { p },
+            ];
+            put_block(out, out_width, x, y, &block);
+        }
+    }
+}
+
+fn scale3x(source: &Source, out: &mut [u32]) {
+    let out_width = source.width * 3;
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let [a, b, c, d, e, f, g, h, i] = neighbourhood(source, x, y);
+            let block = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            put_block(out, out_width, x, y, &block);
+        }
+    }
+}
+
+// The 3x3 pixels around (x, y), row by row
+fn neighbourhood(source: &Source, x: usize, y: usize) -> [u32; 9] {
+    std::array::from_fn(|i| source.at(x, y, i as isize % 3 - 1, i as isize / 3 - 1))
+}
+
+// Colors count as different when they are far apart in YUV, with hq2x's
+// thresholds
+fn differs(a: u32, b: u32) -> bool {
+    let yuv = |color: u32| {
+        let (r, g, b) = ((color >> 16 & 0xFF) as i32, (color >> 8 & 0xFF) as i32, (color & 0xFF) as i32);
+        (
+            (299 * r + 587 * g + 114 * b) / 1000,
+            (-169 * r - 331 * g + 500 * b) / 1000,
+            (500 * r - 419 * g - 81 * b) / 1000,
+        )
+    };
+    let ((y1, u1, v1), (y2, u2, v2)) = (yuv(a), yuv(b));
+    (y1 - y2).abs() > 0x30 || (u1 - u2).abs() > 0x07 || (v1 - v2).abs() > 0x06
+}
+
+// The neighbours hq2x compares with the center, as indices into
+// neighbourhood(). Bit n of a pattern is set when NEIGHBOURS[n] differs.
+const NEIGHBOURS: [usize; 8] = [0, 1, 2, 3, 5, 6, 7, 8];
+
+// For the top-left, top-right, bottom-left and bottom-right output pixels:
+// the diagonal neighbour they face, then the two side neighbours
+const HQ2X_CORNERS: [(usize, usize, usize); 4] = [(0, 3, 1), (2, 1, 5), (6, 7, 3), (8, 5, 7)];
+
+const TL: usize = 0;
+const TR: usize = 1;
+const BL: usize = 2;
+const BR: usize = 3;
+
+// How an output pixel mixes the center with the diagonal neighbour and the
+// two side neighbours of its corner, named after the PIXELxx_nn macros of
+// the reference hq2x
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mix {
+    M0,
+    M10,
+    M11,
+    M12,
+    M20,
+    M21,
+    M22,
+    M60,
+    M61,
+    M70,
+    M90,
+    M100,
+}
+
+impl Mix {
+    fn apply(self, center: u32, diagonal: u32, first: u32, second: u32) -> u32 {
+        let e = center;
+        match self {
+            Mix::M0 => e,
+            Mix::M10 => interpolate(&[(e, 3), (diagonal, 1)], 2),
+            Mix::M11 => interpolate(&[(e, 3), (first, 1)], 2),
+            Mix::M12 => interpolate(&[(e, 3), (second, 1)], 2),
+            Mix::M20 => interpolate(&[(e, 2), (first, 1), (second, 1)], 2),
+            Mix::M21 => interpolate(&[(e, 2), (diagonal, 1), (second, 1)], 2),
+            Mix::M22 => interpolate(&[(e, 2), (diagonal, 1), (first, 1)], 2),
+            Mix::M60 => interpolate(&[(e, 5), (second, 2), (first, 1)], 3),
+            Mix::M61 => interpolate(&[(e, 5), (first, 2), (second, 1)], 3),
+            Mix::M70 => interpolate(&[(e, 6), (first, 1), (second, 1)], 3),
+            Mix::M90 => interpolate(&[(e, 2), (first, 3), (second, 3)], 3),
+            Mix::M100 => interpolate(&[(e, 14), (first, 1), (second, 1)], 4),
+        }
+    }
+}
+
+// The mix for one output pixel. With a corner, `differ` is used when the two
+// side neighbours of that corner differ from each other and `same` when not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rule {
+    corner: Option<usize>,
+    differ: Mix,
+    same: Mix,
+}
+
+fn is(mix: Mix) -> Rule {
+    Rule { corner: None, differ: mix, same: mix }
+}
+
+fn on(corner: usize, differ: Mix, same: Mix) -> Rule {
+    Rule { corner: Some(corner), differ, same }
+}
+
+// hq2x's interpolation table: the rules for the four output pixels for each
+// pattern of neighbours that differ from the center
+fn hq2x_rules(pattern: u8) -> [Rule; 4] {
+    use Mix::*;
+    match pattern {
+        0 | 1 | 4 | 32 | 128 | 5 | 132 | 160 | 33 | 129 | 36 | 133 | 164 | 161 | 37 | 165 => [is(M20), is(M20), is(M20), is(M20)],
+        2 | 34 | 130 | 162 => [is(M22), is(M21), is(M20), is(M20)],
+        16 | 17 | 48 | 49 => [is(M20), is(M22), is(M20), is(M21)],
+        64 | 65 | 68 | 69 => [is(M20), is(M20), is(M21), is(M22)],
+        8 | 12 | 136 | 140 => [is(M21), is(M20), is(M22), is(M20)],
+        3 | 35 | 131 | 163 => [is(M11), is(M21), is(M20), is(M20)],
+        6 | 38 | 134 | 166 => [is(M22), is(M12), is(M20), is(M20)],
+        20 | 21 | 52 | 53 => [is(M20), is(M11), is(M20), is(M21)],
+        144 | 145 | 176 | 177 => [is(M20), is(M22), is(M20), is(M12)],
+        192 | 193 | 196 | 197 => [is(M20), is(M20), is(M21), is(M11)],
+        96 | 97 | 100 | 101 => [is(M20), is(M20), is(M12), is(M22)],
+        40 | 44 | 168 | 172 => [is(M21), is(M20), is(M11), is(M20)],
+        9 | 13 | 137 | 141 => [is(M12), is(M20), is(M22), is(M20)],
+        18 | 50 => [is(M22), on(TR, M10, M20), is(M20), is(M21)],
+        80 | 81 => [is(M20), is(M22), is(M21), on(BR, M10, M20)],
+        72 | 76 => [is(M21), is(M20), on(BL, M10, M20), is(M22)],
+        10 | 138 => [on(TL, M10, M20), is(M21), is(M22), is(M20)],
+        66 => [is(M22), is(M21), is(M21), is(M22)],
+        24 => [is(M21), is(M22), is(M22), is(M21)],
+        7 | 39 | 135 => [is(M11), is(M12), is(M20), is(M20)],
+        148 | 149 | 180 => [is(M20), is(M11), is(M20), is(M12)],
+        224 | 228 | 225 => [is(M20), is(M20), is(M12), is(M11)],
+        41 | 169 | 45 => [is(M12), is(M20), is(M11), is(M20)],
+        22 | 54 => [is(M22), on(TR, M0, M20), is(M20), is(M21)],
+        208 | 209 => [is(M20), is(M22), is(M21), on(BR, M0, M20)],
+        104 | 108 => [is(M21), is(M20), on(BL, M0, M20), is(M22)],
+        11 | 139 => [on(TL, M0, M20), is(M21), is(M22), is(M20)],
+        19 | 51 => [on(TR, M11, M60), on(TR, M10, M90), is(M20), is(M21)],
+        146 | 178 => [is(M22), on(TR, M10, M90), is(M20), on(TR, M12, M61)],
+        84 | 85 => [is(M20), on(BR, M11, M60), is(M21), on(BR, M10, M90)],
+        112 | 113 => [is(M20), is(M22), on(BR, M12, M61), on(BR, M10, M90)],
+        200 | 204 => [is(M21), is(M20), on(BL, M10, M90), on(BL, M11, M60)],
+        73 | 77 => [on(BL, M12, M61), is(M20), on(BL, M10, M90), is(M22)],
+        42 | 170 => [on(TL, M10, M90), is(M21), on(TL, M11, M60), is(M20)],
+        14 | 142 => [on(TL, M10, M90), on(TL, M12, M61), is(M22), is(M20)],
+        67 => [is(M11), is(M21), is(M21), is(M22)],
+        70 => [is(M22), is(M12), is(M21), is(M22)],
+        28 => [is(M21), is(M11), is(M22), is(M21)],
+        152 => [is(M21), is(M22), is(M22), is(M12)],
+        194 => [is(M22), is(M21), is(M21), is(M11)],
+        98 => [is(M22), is(M21), is(M12), is(M22)],
+        56 => [is(M21), is(M22), is(M11), is(M21)],
+        25 => [is(M12), is(M22), is(M22), is(M21)],
+        26 | 31 => [on(TL, M0, M20), on(TR, M0, M20), is(M22), is(M21)],
+        82 | 214 => [is(M22), on(TR, M0, M20), is(M21), on(BR, M0, M20)],
+        88 | 248 => [is(M21), is(M22), on(BL, M0, M20), on(BR, M0, M20)],
+        74 | 107 => [on(TL, M0, M20), is(M21), on(BL, M0, M20), is(M22)],
+        27 => [on(TL, M0, M20), is(M10), is(M22), is(M21)],
+        86 => [is(M22), on(TR, M0, M20), is(M21), is(M10)],
+        216 => [is(M21), is(M22), is(M10), on(BR, M0, M20)],
+        106 => [is(M10), is(M21), on(BL, M0, M20), is(M22)],
+        30 => [is(M10), on(TR, M0, M20), is(M22), is(M21)],
+        210 => [is(M22), is(M10), is(M21), on(BR, M0, M20)],
+        120 => [is(M21), is(M22), on(BL, M0, M20), is(M10)],
+        75 => [on(TL, M0, M20), is(M21), is(M10), is(M22)],
+        29 => [is(M12), is(M11), is(M22), is(M21)],
+        198 => [is(M22), is(M12), is(M21), is(M11)],
+        184 => [is(M21), is(M22), is(M11), is(M12)],
+        99 => [is(M11), is(M21), is(M12), is(M22)],
+        57 => [is(M12), is(M22), is(M11), is(M21)],
+        71 => [is(M11), is(M12), is(M21), is(M22)],
+        156 => [is(M21), is(M11), is(M22), is(M12)],
+        226 => [is(M22), is(M21), is(M12), is(M11)],
+        60 => [is(M21), is(M11), is(M11), is(M21)],
+        195 => [is(M11), is(M21), is(M21), is(M11)],
+        102 => [is(M22), is(M12), is(M12), is(M22)],
+        153 => [is(M12), is(M22), is(M22), is(M12)],
+        58 => [on(TL, M10, M70), on(TR, M10, M70), is(M11), is(M21)],
+        83 => [is(M11), on(TR, M10, M70), is(M21), on(BR, M10, M70)],
+        92 => [is(M21), is(M11), on(BL, M10, M70), on(BR, M10, M70)],
+        202 => [on(TL, M10, M70), is(M21), on(BL, M10, M70), is(M11)],
+        78 => [on(TL, M10, M70), is(M12), on(BL, M10, M70), is(M22)],
+        154 => [on(TL, M10, M70), on(TR, M10, M70), is(M22), is(M12)],
+        114 => [is(M22), on(TR, M10, M70), is(M12), on(BR, M10, M70)],
+        89 => [is(M12), is(M22), on(BL, M10, M70), on(BR, M10, M70)],
+        90 => [on(TL, M10, M70), on(TR, M10, M70), on(BL, M10, M70), on(BR, M10, M70)],
+        55 | 23 => [on(TR, M11, M60), on(TR, M0, M90), is(M20), is(M21)],
+        182 | 150 => [is(M22), on(TR, M0, M90), is(M20), on(TR, M12, M61)],
+        213 | 212 => [is(M20), on(BR, M11, M60), is(M21), on(BR, M0, M90)],
+        241 | 240 => [is(M20), is(M22), on(BR, M12, M61), on(BR, M0, M90)],
+        236 | 232 => [is(M21), is(M20), on(BL, M0, M90), on(BL, M11, M60)],
+        109 | 105 => [on(BL, M12, M61), is(M20), on(BL, M0, M90), is(M22)],
+        171 | 43 => [on(TL, M0, M90), is(M21), on(TL, M11, M60), is(M20)],
+        143 | 15 => [on(TL, M0, M90), on(TL, M12, M61), is(M22), is(M20)],
+        124 => [is(M21), is(M11), on(BL, M0, M20), is(M10)],
+        203 => [on(TL, M0, M20), is(M21), is(M10), is(M11)],
+        62 => [is(M10), on(TR, M0, M20), is(M11), is(M21)],
+        211 => [is(M11), is(M10), is(M21), on(BR, M0, M20)],
+        118 => [is(M22), on(TR, M0, M20), is(M12), is(M10)],
+        217 => [is(M12), is(M22), is(M10), on(BR, M0, M20)],
+        110 => [is(M10), is(M12), on(BL, M0, M20), is(M22)],
+        155 => [on(TL, M0, M20), is(M10), is(M22), is(M12)],
+        188 => [is(M21), is(M11), is(M11), is(M12)],
+        185 => [is(M12), is(M22), is(M11), is(M12)],
+        61 => [is(M12), is(M11), is(M11), is(M21)],
+        157 => [is(M12), is(M11), is(M22), is(M12)],
+        103 => [is(M11), is(M12), is(M12), is(M22)],
+        227 => [is(M11), is(M21), is(M12), is(M11)],
+        230 => [is(M22), is(M12), is(M12), is(M11)],
+        199 => [is(M11), is(M12), is(M21), is(M11)],
+        220 => [is(M21), is(M11), on(BL, M10, M70), on(BR, M0, M20)],
+        158 => [on(TL, M10, M70), on(TR, M0, M20), is(M22), is(M12)],
+        234 => [on(TL, M10, M70), is(M21), on(BL, M0, M20), is(M11)],
+        242 => [is(M22), on(TR, M10, M70), is(M12), on(BR, M0, M20)],
+        59 => [on(TL, M0, M20), on(TR, M10, M70), is(M11), is(M21)],
+        121 => [is(M12), is(M22), on(BL, M0, M20), on(BR, M10, M70)],
+        87 => [is(M11), on(TR, M0, M20), is(M21), on(BR, M10, M70)],
+        79 => [on(TL, M0, M20), is(M12), on(BL, M10, M70), is(M22)],
+        122 => [on(TL, M10, M70), on(TR, M10, M70), on(BL, M0, M20), on(BR, M10, M70)],
+        94 => [on(TL, M10, M70), on(TR, M0, M20), on(BL, M10, M70), on(BR, M10, M70)],
+        218 => [on(TL, M10, M70), on(TR, M10, M70), on(BL, M10, M70), on(BR, M0, M20)],
+        91 => [on(TL, M0, M20), on(TR, M10, M70), on(BL, M10, M70), on(BR, M10, M70)],
+        229 => [is(M20), is(M20), is(M12), is(M11)],
+        167 => [is(M11), is(M12), is(M20), is(M20)],
+        173 => [is(M12), is(M20), is(M11), is(M20)],
+        181 => [is(M20), is(M11), is(M20), is(M12)],
+        186 => [on(TL, M10, M70), on(TR, M10, M70), is(M11), is(M12)],
+        115 => [is(M11), on(TR, M10, M70), is(M12), on(BR, M10, M70)],
+        93 => [is(M12), is(M11), on(BL, M10, M70), on(BR, M10, M70)],
+        206 => [on(TL, M10, M70), is(M12), on(BL, M10, M70), is(M11)],
+        205 | 201 => [is(M12), is(M20), on(BL, M10, M70), is(M11)],
+        174 | 46 => [on(TL, M10, M70), is(M12), is(M11), is(M20)],
+        179 | 147 => [is(M11), on(TR, M10, M70), is(M20), is(M12)],
+        117 | 116 => [is(M20), is(M11), is(M12), on(BR, M10, M70)],
+        189 => [is(M12), is(M11), is(M11), is(M12)],
+        231 => [is(M11), is(M12), is(M12), is(M11)],
+        126 => [is(M10), on(TR, M0, M20), on(BL, M0, M20), is(M10)],
+        219 => [on(TL, M0, M20), is(M10), is(M10), on(BR, M0, M20)],
+        125 => [on(BL, M12, M61), is(M11), on(BL, M0, M90), is(M10)],
+        221 => [is(M12), on(BR, M11, M60), is(M10), on(BR, M0, M90)],
+        207 => [on(TL, M0, M90), on(TL, M12, M61), is(M10), is(M11)],
+        238 => [is(M10), is(M12), on(BL, M0, M90), on(BL, M11, M60)],
+        190 => [is(M10), on(TR, M0, M90), is(M11), on(TR, M12, M61)],
+        187 => [on(TL, M0, M90), is(M10), on(TL, M11, M60), is(M12)],
+        243 => [is(M11), is(M10), on(BR, M12, M61), on(BR, M0, M90)],
+        119 => [on(TR, M11, M60), on(TR, M0, M90), is(M12), is(M10)],
+        237 | 233 => [is(M12), is(M20), on(BL, M0, M100), is(M11)],
+        175 | 47 => [on(TL, M0, M100), is(M12), is(M11), is(M20)],
+        183 | 151 => [is(M11), on(TR, M0, M100), is(M20), is(M12)],
+        245 | 244 => [is(M20), is(M11), is(M12), on(BR, M0, M100)],
+        250 => [is(M10), is(M10), on(BL, M0, M20), on(BR, M0, M20)],
+        123 => [on(TL, M0, M20), is(M10), on(BL, M0, M20), is(M10)],
+        95 => [on(TL, M0, M20), on(TR, M0, M20), is(M10), is(M10)],
+        222 => [is(M10), on(TR, M0, M20), is(M10), on(BR, M0, M20)],
+        252 => [is(M21), is(M11), on(BL, M0, M20), on(BR, M0, M100)],
+        249 => [is(M12), is(M22), on(BL, M0, M100), on(BR, M0, M20)],
+        235 => [on(TL, M0, M20), is(M21), on(BL, M0, M100), is(M11)],
+        111 => [on(TL, M0, M100), is(M12), on(BL, M0, M20), is(M22)],
+        63 => [on(TL, M0, M100), on(TR, M0, M20), is(M11), is(M21)],
+        159 => [on(TL, M0, M20), on(TR, M0, M100), is(M22), is(M12)],
+        215 => [is(M11), on(TR, M0, M100), is(M21), on(BR, M0, M20)],
+        246 => [is(M22), on(TR, M0, M20), is(M12), on(BR, M0, M100)],
+        254 => [is(M10), on(TR, M0, M20), on(BL, M0, M20), on(BR, M0, M100)],
+        253 => [is(M12), is(M11), on(BL, M0, M100), on(BR, M0, M100)],
+        251 => [on(TL, M0, M20), is(M10), on(BL, M0, M100), on(BR, M0, M20)],
+        239 => [on(TL, M0, M100), is(M12), on(BL, M0, M100), is(M11)],
+        127 => [on(TL, M0, M100), on(TR, M0, M20), on(BL, M0, M20), is(M10)],
+        191 => [on(TL, M0, M100), on(TR, M0, M100), is(M11), is(M12)],
+        223 => [on(TL, M0, M20), on(TR, M0, M100), is(M10), on(BR, M0, M20)],
+        247 => [is(M11), on(TR, M0, M100), is(M12), on(BR, M0, M100)],
+        255 => [on(TL, M0, M100), on(TR, M0, M100), on(BL, M0, M100), on(BR, M0, M100)],
+    }
+}
+
+fn hq2x(source: &Source, out: &mut [u32]) {
+    let out_width = source.width * 2;
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let w = neighbourhood(source, x, y);
+            let center = w[4];
+            let pattern = NEIGHBOURS.iter().enumerate()
+                .filter(|&(_, &i)| differs(center, w[i]))
+                .fold(0u8, |pattern, (bit, _)| pattern | 1 << bit);
+            let rules = hq2x_rules(pattern);
+            let block: [u32; 4] = std::array::from_fn(|pixel| {
+                let rule = rules[pixel];
+                let mix = match rule.corner {
+                    Some(corner) => {
+                        let (_, first, second) = HQ2X_CORNERS[corner];
+                        if differs(w[first], w[second]) { rule.differ } else { rule.same }
+                    }
+                    None => rule.differ,
+                };
+                let (diagonal, first, second) = HQ2X_CORNERS[pixel];
+                mix.apply(center, w[diagonal], w[first], w[second])
+            });
+            put_block(out, out_width, x, y, &block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: u32 = 0x000000;
+    const WHITE: u32 = 0xFFFFFF;
+
+    // Rows of '#' for black and '.' for white
+    fn image(rows: &[&str]) -> Vec<u32> {
+        rows.concat().chars().map(|pixel| if pixel == '#' { BLACK } else { WHITE }).collect()
+    }
+
+    const DIAGONAL: [&str; 3] = [
+        "#..",
+        ".#.",
+        "..#",
+    ];
+
+    #[test]
+    fn parses_filter_names() {
+        assert_eq!(Filter::parse("none"), Some(Filter::None));
+        assert_eq!(Filter::parse("nearest"), Some(Filter::Nearest(3)));
+        assert_eq!(Filter::parse("nearest:4"), Some(Filter::Nearest(4)));
+        assert_eq!(Filter::parse("hq2x"), Some(Filter::Hq2x));
+        assert_eq!(Filter::parse("lcd:1"), Some(Filter::LcdGrid(2)));
+        assert_eq!(Filter::parse("nearest:9"), None);
+        assert_eq!(Filter::parse("scale2x:2"), None);
+        assert_eq!(Filter::parse("smooth2x"), None);
+    }
+
+    #[test]
+    fn scale2x_rounds_off_a_diagonal() {
+        let mut filter = VideoFilter::new(Filter::Scale2x);
+        let output = filter.apply(&image(&DIAGONAL), 3, 3);
+        assert_eq!(output, image(&[
+            "##....",
+            "#.#...",
+            ".###..",
+            "..###.",
+            "...#.#",
+            "....##",
+        ]));
+    }
+
+    #[test]
+    fn scale3x_rounds_off_a_diagonal() {
+        let mut filter = VideoFilter::new(Filter::Scale3x);
+        let output = filter.apply(&image(&DIAGONAL), 3, 3);
+        assert_eq!(output, image(&[
+            "###......",
+            "##.#.....",
+            "#..#.....",
+            ".#####...",
+            "...###...",
+            "...#####.",
+            ".....#..#",
+            ".....#.##",
+            "......###",
+        ]));
+    }
+
+    #[test]
+    fn scalers_leave_flat_areas_alone() {
+        for filter in [Filter::Scale2x, Filter::Scale3x, Filter::Hq2x] {
+            let mut video = VideoFilter::new(filter);
+            let output = video.apply(&[0x306230; 16], 4, 4);
+            assert!(output.iter().all(|&pixel| pixel == 0x306230), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn hq2x_blends_along_a_diagonal() {
+        let mut filter = VideoFilter::new(Filter::Hq2x);
+        let output = filter.apply(&image(&DIAGONAL), 3, 3);
+        let gray = 0x7F7F7F;
+        assert_eq!([output[14], output[15], output[20], output[21]], [BLACK, gray, gray, BLACK]);
+    }
+
+    // Mirroring or rotating the 3x3 neighbourhood has to mirror or rotate the
+    // 2x2 output block, which catches most slips in the table
+    #[test]
+    fn hq2x_rules_are_symmetric() {
+        for mirrored in [false, true] {
+            // Rotating 90 degrees keeps the roles of the two side neighbours,
+            // mirroring along the diagonal swaps them
+            let position = |i: usize| if mirrored { i % 3 * 3 + i / 3 } else { i % 3 * 3 + 2 - i / 3 };
+            let mix = |mix: Mix| match (mirrored, mix) {
+                (true, Mix::M11) => Mix::M12,
+                (true, Mix::M12) => Mix::M11,
+                (true, Mix::M21) => Mix::M22,
+                (true, Mix::M22) => Mix::M21,
+                (true, Mix::M60) => Mix::M61,
+                (true, Mix::M61) => Mix::M60,
+                (_, mix) => mix,
+            };
+            let corner = |corner: usize| {
+                HQ2X_CORNERS.iter().position(|&(diagonal, _, _)| diagonal == position(HQ2X_CORNERS[corner].0)).unwrap()
+            };
+            for pattern in 0..=255u8 {
+                let moved = (0..8).filter(|bit| pattern & 1 << bit != 0).fold(0u8, |moved, bit| {
+                    moved | 1 << NEIGHBOURS.iter().position(|&i| i == position(NEIGHBOURS[bit])).unwrap()
+                });
+                let (rules, moved_rules) = (hq2x_rules(pattern), hq2x_rules(moved));
+                for (pixel, rule) in rules.into_iter().enumerate() {
+                    let expected = Rule { corner: rule.corner.map(corner), differ: mix(rule.differ), same: mix(rule.same) };
+                    assert_eq!(moved_rules[corner(pixel)], expected, "pattern {} moved to {}", pattern, moved);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lcd_grid_darkens_the_last_row_and_column_of_each_block() {
+        let mut filter = VideoFilter::new(Filter::LcdGrid(3));
+        assert_eq!(filter.output_size(160, 144), (480, 432));
+        let output = filter.apply(&[WHITE; 4], 2, 2);
+        assert_eq!(output.len(), 36);
+        let gap = 0x9F9F9F;
+        for (i, &pixel) in output.iter().enumerate() {
+            let (x, y) = (i % 6, i / 6);
+            let expected = if x % 3 == 2 || y % 3 == 2 { gap } else { WHITE };
+            assert_eq!(pixel, expected, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn ghosting_blends_with_the_previous_frame() {
+        let mut filter = VideoFilter::new(Filter::None);
+        filter.set_ghosting(true);
+        assert_eq!(filter.apply(&[WHITE], 1, 1), [WHITE]);
+        assert_eq!(filter.apply(&[BLACK], 1, 1), [0x7F7F7F]);
+        assert_eq!(filter.apply(&[BLACK], 1, 1), [BLACK]);
+    }
+}
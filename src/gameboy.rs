@@ -0,0 +1,144 @@
+use crate::color::PixelFormat;
+use crate::colorize::CompatPalette;
+use crate::cpu::{Registers, CPU};
+use crate::filter::{Filter, VideoFilter};
+use crate::memory::MMU;
+use crate::screenshot::Image;
+use std::fs;
+use std::io;
+
+// Clock cycles per second, and in one 59.73 Hz frame
+pub const CLOCK_HZ: u32 = 4_194_304;
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+pub struct Gameboy {
+    pub cpu: CPU,
+    pub memory: MMU,
+}
+
+impl Gameboy {
+    pub fn new(rom_path: &str) -> Result<Self, io::Error> {
+        Ok(Self::from_rom(fs::read(rom_path)?))
+    }
+
+    pub fn from_rom(rom: Vec<u8>) -> Self {
+        // Header byte 0x143 has bit 7 set for games that support the CGB
+        let cgb = rom.get(0x143).is_some_and(|&flags| flags & 0x80 != 0);
+        let mut memory = MMU::new();
+        memory.load_rom_data(rom);
+        memory.set_cgb_mode(cgb);
+
+        let mut gameboy = Gameboy {
+            cpu: CPU::new(),
+            memory,
+        };
+        gameboy.skip_boot_rom();
+        gameboy
+    }
+
+    // Put the machine in the state the DMG or CGB boot ROM leaves it in
+    fn skip_boot_rom(&mut self) {
+        let mut registers = Registers {
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ..Registers::default()
+        };
+        if self.memory.is_cgb() {
+            registers.set_af(0x1180);
+            registers.set_bc(0x0000);
+            registers.set_de(0xFF56);
+            registers.set_hl(0x000D);
+        } else {
+            registers.set_af(0x01B0);
+            registers.set_bc(0x0013);
+            registers.set_de(0x00D8);
+            registers.set_hl(0x014D);
+        }
+        self.cpu.set_registers(registers);
+
+        self.memory.write_byte(0xFF40, 0x91); // LCDC
+        self.memory.write_byte(0xFF47, 0xFC); // BGP
+        self.memory.write_byte(0xFF50, 0x01); // Unmap the boot ROM
+    }
+
+    // Run one instruction (or interrupt dispatch) and advance the hardware
+    pub fn step(&mut self) -> u32 {
+        self.cpu.advance(&mut self.memory)
+    }
+
+    // Run until the PPU enters VBlank, so each call shows one whole frame.
+    // With the LCD off there are no frames, so stop after a frame's worth of
+    // cycles instead to keep the window responsive.
+    pub fn run_frame(&mut self) {
+        let frame_cycles = if self.memory.is_double_speed() { 2 * CYCLES_PER_FRAME } else { CYCLES_PER_FRAME };
+        self.memory.ppu_mut().take_frame_complete();
+        let mut cycles = 0;
+        loop {
+            cycles += self.step();
+            let ppu = self.memory.ppu_mut();
+            if ppu.take_frame_complete() || (!ppu.is_lcd_enabled() && cycles >= frame_cycles) {
+                break;
+            }
+        }
+    }
+
+    // Show a DMG game in color, as a CGB would. CGB games have their own
+    // palettes and are left alone.
+    pub fn set_compat_palette(&mut self, palette: Option<CompatPalette>) {
+        if !self.memory.is_cgb() {
+            self.memory.ppu_mut().set_compat_palette(palette.as_ref());
+        }
+    }
+
+    pub fn get_frame_buffer(&self) -> Vec<u32> {
+        self.memory.ppu().get_frame_buffer()
+    }
+
+    pub fn write_frame(&self, format: PixelFormat, out: &mut [u8]) {
+        self.memory.ppu().write_frame(format, out)
+    }
+
+    pub fn write_frame_xrgb(&self, out: &mut [u32]) {
+        self.memory.ppu().write_frame_xrgb(out)
+    }
+
+    // The current frame, at native size with Filter::None or scaled by any
+    // other filter
+    pub fn screenshot(&self, filter: Filter) -> Image {
+        let frame = self.get_frame_buffer();
+        let mut video_filter = VideoFilter::new(filter);
+        let (width, height) = video_filter.output_size(160, 144);
+        Image::from_xrgb(width, height, video_filter.apply(&frame, 160, 144))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_end_when_vblank_starts() {
+        let mut gameboy = Gameboy::from_rom(vec![0; 0x8000]);
+        for _ in 0..3 {
+            gameboy.run_frame();
+            assert_eq!(gameboy.memory.read_byte(0xFF44), 144);
+        }
+    }
+
+    #[test]
+    fn frames_still_end_with_the_lcd_off() {
+        let mut gameboy = Gameboy::from_rom(vec![0; 0x8000]);
+        gameboy.memory.write_byte(0xFF40, 0x00);
+        gameboy.run_frame();
+        assert_eq!(gameboy.memory.read_byte(0xFF44), 0);
+    }
+
+    #[test]
+    fn screenshots_come_out_at_the_filter_scale() {
+        let gameboy = Gameboy::from_rom(vec![0; 0x8000]);
+        let image = gameboy.screenshot(Filter::Scale2x);
+        assert_eq!((image.width, image.height), (320, 288));
+        assert_eq!(image.pixels.len(), 320 * 288);
+        assert_eq!(gameboy.screenshot(Filter::None).pixels.len(), 160 * 144);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::color::PixelFormat;
+use crate::cpu::Registers;
+use crate::gameboy::{Gameboy, CYCLES_PER_FRAME};
+use crate::ppu::Renderer;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Cycles to keep running after "Failed" so the details get printed too
+const FAILURE_GRACE_CYCLES: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub rom: PathBuf,
+    pub outcome: Outcome,
+    pub output: String,
+    pub cycles: u64,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+// Directory holding the test ROM suites, overridable with RUSTY_BOY_TEST_ROMS
+pub fn test_rom_dir() -> PathBuf {
+    match env::var_os("RUSTY_BOY_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+    }
+}
+
+// Every .gb/.gbc file under `dir`, sorted by path
+pub fn find_roms(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            roms.extend(find_roms(&path)?);
+        } else if matches!(path.extension().and_then(|ext| ext.to_str()), Some("gb" | "gbc")) {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+// Run a ROM that reports through the serial port, as Blargg's suites do,
+// until it prints "Passed" or "Failed" or the cycle budget runs out
+pub fn run_serial_test(rom: &Path, cycle_budget: u64) -> io::Result<TestResult> {
+    let mut gameboy = Gameboy::from_rom(fs::read(rom)?);
+    let mut cycles: u64 = 0;
+    let mut failed_at: Option<u64> = None;
+    let mut checked = 0;
+
+    while cycles < cycle_budget {
+        cycles += gameboy.step() as u64;
+
+        let output = gameboy.memory.serial().output();
+        if output.len() != checked {
+            checked = output.len();
+            let text = String::from_utf8_lossy(output);
+            if text.contains("Passed") {
+                return Ok(serial_result(rom, Outcome::Passed, &gameboy, cycles));
+            }
+            if failed_at.is_none() && text.contains("Failed") {
+                failed_at = Some(cycles);
+            }
+        }
+        if failed_at.is_some_and(|at| cycles - at >= FAILURE_GRACE_CYCLES) {
+            break;
+        }
+    }
+
+    let outcome = if failed_at.is_some() { Outcome::Failed } else { Outcome::Timeout };
+    Ok(serial_result(rom, outcome, &gameboy, cycles))
+}
+
+fn serial_result(rom: &Path, outcome: Outcome, gameboy: &Gameboy, cycles: u64) -> TestResult {
+    TestResult {
+        rom: rom.to_path_buf(),
+        outcome,
+        output: String::from_utf8_lossy(gameboy.memory.serial().output()).into_owned(),
+        cycles,
+    }
+}
+
+// Mooneye ROMs load the Fibonacci numbers into B/C/D/E/H/L on success and
+// 0x42 into all of them on failure, then execute LD B,B
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+// Run a Mooneye test ROM until its LD B,B breakpoint and check the
+// register signature
+pub fn run_mooneye_test(rom: &Path, cycle_budget: u64) -> io::Result<TestResult> {
+    let mut gameboy = Gameboy::from_rom(fs::read(rom)?);
+    let mut cycles: u64 = 0;
+
+    while cycles < cycle_budget {
+        cycles += gameboy.step() as u64;
+        if !gameboy.cpu.take_breakpoint() {
+            continue;
+        }
+
+        let registers = gameboy.cpu.registers();
+        let signature = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+        let outcome = if signature == MOONEYE_PASS { Outcome::Passed } else { Outcome::Failed };
+        let mut output = describe_registers(&registers);
+        if signature == MOONEYE_FAIL {
+            output.insert_str(0, "Test reported failure\n");
+        }
+        return Ok(TestResult { rom: rom.to_path_buf(), outcome, output, cycles });
+    }
+
+    let output = describe_registers(&gameboy.cpu.registers());
+    Ok(TestResult { rom: rom.to_path_buf(), outcome: Outcome::Timeout, output, cycles })
+}
+
+fn describe_registers(registers: &Registers) -> String {
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+        registers.a, registers.f, registers.b, registers.c, registers.d,
+        registers.e, registers.h, registers.l, registers.sp, registers.pc,
+    )
+}
+
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,       // RGB, row by row
+    pub frames: u32,                // frames run before the capture
+    pub breakpoint: bool,           // false if the frame limit ran out first
+}
+
+// Run a ROM until it executes LD B,B or `frame_limit` frames pass, then
+// finish the frame in progress and draw one more so every line on screen
+// shows the final state
+pub fn run_screenshot_test(rom: &Path, frame_limit: u32, renderer: Renderer) -> io::Result<Screenshot> {
+    let mut gameboy = Gameboy::from_rom(fs::read(rom)?);
+    gameboy.memory.ppu_mut().set_renderer(renderer);
+    let mut frames = 0;
+    let mut breakpoint = false;
+
+    'frames: while frames < frame_limit {
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            cycles += gameboy.step();
+            if gameboy.cpu.take_breakpoint() {
+                breakpoint = true;
+                break 'frames;
+            }
+        }
+        frames += 1;
+    }
+    gameboy.run_frame();
+    gameboy.run_frame();
+
+    let mut frame = vec![0; 160 * 144 * 4];
+    gameboy.write_frame(PixelFormat::Rgba8888, &mut frame);
+    let pixels = frame.chunks_exact(4).map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+    Ok(Screenshot { width: 160, height: 144, pixels, frames, breakpoint })
+}
+
+// Mooneye file names end in the models a test is meant for, e.g.
+// "boot_regs-dmgABC" or "di_timing-GS"; no suffix means every model
+pub fn runs_on_dmg(rom: &Path) -> bool {
+    let name = rom.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+    let Some((_, models)) = name.rsplit_once('-') else {
+        return true;
+    };
+    if let Some(revisions) = models.strip_prefix("dmg") {
+        return revisions.chars().any(|revision| "ABC".contains(revision));
+    }
+    if ["mgb", "sgb", "cgb", "agb", "ags"].iter().any(|model| models.starts_with(model)) {
+        return false;
+    }
+    // Single letters: G = DMG/MGB, S = SGB, C = CGB, A = AGB
+    if models.chars().all(|model| "GSCA".contains(model)) {
+        return models.contains('G');
+    }
+    true
+}
+
+// One line per ROM, followed by the output of everything that did not pass
+pub fn report(results: &[TestResult]) -> String {
+    let mut report = String::new();
+    for result in results {
+        report.push_str(&format!("{:?}: {} ({} cycles)\n", result.outcome, result.rom.display(), result.cycles));
+    }
+    for result in results.iter().filter(|result| !result.passed()) {
+        report.push_str(&format!("\n--- {} ---\n{}\n", result.rom.display(), result.output.trim_end()));
+    }
+    let passed = results.iter().filter(|result| result.passed()).count();
+    report.push_str(&format!("\n{}/{} passed\n", passed, results.len()));
+    report
+}
@@ -11,6 +11,12 @@ pub struct InterruptController {
     ime: bool,
 }
 
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InterruptController {
     pub fn new() -> Self {
         InterruptController {
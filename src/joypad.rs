@@ -0,0 +1,94 @@
+use crate::interrupts::JOYPAD_INTERRUPT;
+use crate::peripheral::Peripheral;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+pub struct Joypad {
+    select: u8,      // P1 bits 4-5, a 0 selects that button group
+    directions: u8,  // Right, Left, Up, Down in bits 0-3, 1 = pressed
+    actions: u8,     // A, B, Select, Start in bits 0-3, 1 = pressed
+    interrupt: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: 0x30,
+            directions: 0,
+            actions: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let before = self.lines();
+        let (group, bit) = match button {
+            Button::Right => (&mut self.directions, 0),
+            Button::Left => (&mut self.directions, 1),
+            Button::Up => (&mut self.directions, 2),
+            Button::Down => (&mut self.directions, 3),
+            Button::A => (&mut self.actions, 0),
+            Button::B => (&mut self.actions, 1),
+            Button::Select => (&mut self.actions, 2),
+            Button::Start => (&mut self.actions, 3),
+        };
+        if pressed {
+            *group |= 1 << bit;
+        } else {
+            *group &= !(1 << bit);
+        }
+
+        // The interrupt fires when a selected line goes from high to low
+        if self.lines() & !before != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    // Pressed buttons in the selected groups, 1 = pressed
+    fn lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & 0x10 == 0 {
+            lines |= self.directions;
+        }
+        if self.select & 0x20 == 0 {
+            lines |= self.actions;
+        }
+        lines
+    }
+}
+
+impl Peripheral for Joypad {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[0xFF00..=0xFF00];
+        RANGES
+    }
+
+    fn read_byte(&self, _addr: u16) -> u8 {
+        0xC0 | self.select | (!self.lines() & 0x0F)
+    }
+
+    fn write_byte(&mut self, _addr: u16, value: u8) {
+        self.select = value & 0x30;
+    }
+
+    fn take_interrupts(&mut self) -> u8 {
+        if std::mem::replace(&mut self.interrupt, false) { JOYPAD_INTERRUPT } else { 0 }
+    }
+}
@@ -0,0 +1,25 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod color;
+pub mod colorize;
+pub mod cpu;
+pub mod disasm;
+pub mod dma;
+pub mod filter;
+pub mod gameboy;
+pub mod harness;
+pub mod interrupts;
+pub mod joypad;
+pub mod memory;
+pub mod peripheral;
+pub mod ppu;
+pub mod recording;
+pub mod rom_disasm;
+pub mod screenshot;
+pub mod serial;
+pub mod timer;
+pub mod trace;
+pub mod trace_diff;
@@ -1,73 +1,302 @@
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 use std::env;
-use std::time::Duration;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-mod cpu;
-mod ppu;
-mod memory;
-mod timer;
-mod interrupts;
-
-use cpu::CPU;
-use memory::MMU;
-use ppu::PPU;
-use timer::Timer;
-use interrupts::InterruptController;
+use rusty_boy::color::{ColorCorrection, DmgPalette};
+use rusty_boy::colorize::{self, CompatPalette};
+use rusty_boy::disasm::SymbolTable;
+use rusty_boy::filter::{self, Filter, VideoFilter};
+use rusty_boy::gameboy::Gameboy;
+use rusty_boy::joypad::Button;
+use rusty_boy::ppu::Renderer;
+use rusty_boy::recording::{Recorder, VideoFormat};
+use rusty_boy::rom_disasm::RomDisassembly;
+use rusty_boy::screenshot::Image;
+use rusty_boy::trace::{self, TraceFilter, Tracer};
+use rusty_boy::trace_diff;
 
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
 const SCALE: usize = 3;
 
-struct Gameboy {
-    cpu: CPU,
-    memory: MMU,
-    ppu: PPU,
-    timer: Timer,
-    interrupt_controller: InterruptController,
+const KEYMAP: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Z, Button::A),
+    (Key::X, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+// F cycles through the video filters, G toggles ghosting
+const FILTER_KEY: Key = Key::F;
+const GHOSTING_KEY: Key = Key::G;
+const SCREENSHOT_KEY: Key = Key::F12;
+const RECORD_KEY: Key = Key::F9;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} <path_to_rom> [--config <file>] [--trace <file>] [--trace-pc <start-end>] [--trace-bank <n>]", program);
+    eprintln!("       {:width$} [--trace-skip <n>] [--trace-count <n>] [--renderer <scanline|fifo>]", "", width = program.len() + 13);
+    eprintln!("       {:width$} [--colorize <auto|up|down|left|right>[+a|+b]]", "", width = program.len() + 13);
+    eprintln!("       {:width$} [--palette <greyscale|green|pocket|light|RRGGBB,RRGGBB,RRGGBB,RRGGBB>]", "", width = program.len() + 13);
+    eprintln!("       {:width$} [--color-correction <off|higan|lcd>]", "", width = program.len() + 13);
+    eprintln!("       {:width$} [--filter <none|nearest[:n]|scale2x|scale3x|hq2x|lcd[:n]>] [--ghosting]", "", width = program.len() + 13);
+    eprintln!("       {:width$} [--screenshot-metadata] [--record <file.png|file.y4m>] [--record-format <apng|y4m>]", "", width = program.len() + 13);
+    eprintln!("       {} disasm <path_to_rom> [--sym <file>] [-o <output.asm>]", program);
+    eprintln!("       {} tracediff <our_trace> <reference_trace> [--context <n>]", program);
+    std::process::exit(1);
 }
 
-impl Gameboy {
-    fn new(rom_path: &str) -> Result<Self, std::io::Error> {
-        let mut memory = MMU::new();
-        memory.load(rom_path)?;
-        println!("ROM loaded successfully");
+// A CGB palette key combo such as "left" or "up+a"
+fn parse_palette_combo(value: &str) -> Option<CompatPalette> {
+    let (direction, button) = match value.split_once('+') {
+        Some((direction, button)) => (direction, Some(button)),
+        None => (value, None),
+    };
+    let direction = match direction {
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "left" => Button::Left,
+        "right" => Button::Right,
+        _ => return None,
+    };
+    let button = match button {
+        None => None,
+        Some("a") => Some(Button::A),
+        Some("b") => Some(Button::B),
+        Some(_) => return None,
+    };
+    colorize::manual_palette(direction, button)
+}
 
-        Ok(Gameboy {
-            cpu: CPU::new(),
-            memory,
-            ppu: PPU::new(),
-            timer: Timer::new(),
-            interrupt_controller: InterruptController::new(),
-        })
+// Options from a config file, turned into command line arguments. Each line
+// is an option without its dashes, then "=" and the value if it takes one:
+//
+//   # Game Boy Pocket-ish screen
+//   palette = e0f8d0, 88c070, 346856, 081820
+//   filter = lcd:4
+//   ghosting
+//
+// Blank lines and lines starting with # are skipped.
+fn config_args(path: &str) -> io::Result<Vec<String>> {
+    let mut args = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) => args.extend([format!("--{}", name.trim()), value.trim().to_string()]),
+            None => args.push(format!("--{}", line)),
+        }
     }
+    Ok(args)
+}
 
-    fn update(&mut self) {
-        let cycles = self.cpu.step(&mut self.memory);
-        println!("CPU executed {} cycles", cycles);
+// The current UTC time as YYYYMMDD-HHMMSS, for file names
+fn timestamp() -> String {
+    format_timestamp(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()))
+}
 
-        self.timer.update(cycles);
-        self.ppu.update(cycles);
+// Seconds since the Unix epoch as YYYYMMDD-HHMMSS in UTC
+fn format_timestamp(seconds: u64) -> String {
+    let (days, time) = ((seconds / 86400) as i64, seconds % 86400);
 
-        let interrupts = self.interrupt_controller.get_interrupts();
-        if interrupts != 0 {
-            self.cpu.handle_interrupts(&mut self.memory, interrupts);
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's algorithm
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153; // March is 0
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + (month <= 2) as i64;
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+// "<rom>-<timestamp>.<extension>" in the ROM's directory, numbered if that
+// name is already taken
+fn capture_path(rom_path: &str, extension: &str) -> PathBuf {
+    let rom_path = Path::new(rom_path);
+    let stem = rom_path.file_stem().map_or("capture".into(), |stem| stem.to_string_lossy());
+    let name = format!("{}-{}", stem, timestamp());
+    let mut path = rom_path.with_file_name(format!("{}.{}", name, extension));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = rom_path.with_file_name(format!("{}-{}.{}", name, count, extension));
+    }
+    path
+}
+
+fn save_screenshot(image: &Image, rom_path: &str, metadata: Option<(&str, u64)>) -> io::Result<PathBuf> {
+    let path = capture_path(rom_path, "png");
+    match metadata {
+        Some((title, frame)) => {
+            let frame = frame.to_string();
+            image.save_png(&path, &[("Title", title), ("Frame", &frame), ("Software", "Rusty Boy")])?;
         }
+        None => image.save_png(&path, &[])?,
     }
+    Ok(path)
+}
 
-    fn get_frame_buffer(&self) -> Vec<u32> {
-        self.ppu.get_frame_buffer()
+fn start_recording(path: &Path, format: VideoFormat, (width, height): (usize, usize)) -> Option<Recorder> {
+    match Recorder::create(path, format, width, height) {
+        Ok(recorder) => {
+            println!("Recording to {}", path.display());
+            Some(recorder)
+        }
+        Err(error) => {
+            eprintln!("Couldn't start recording to {}: {}", path.display(), error);
+            None
+        }
+    }
+}
+
+fn stop_recording(recorder: Recorder) {
+    let frames = recorder.frames();
+    match recorder.finish() {
+        Ok(()) => println!("Recorded {} frames", frames),
+        Err(error) => eprintln!("Couldn't finish recording: {}", error),
+    }
+}
+
+// Statically disassemble a whole ROM into RGBDS source
+fn disasm_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rom_path = None;
+    let mut sym_path = None;
+    let mut output_path = None;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--sym" => sym_path = Some(rest.next().unwrap_or_else(|| usage(&args[0]))),
+            "-o" => output_path = Some(rest.next().unwrap_or_else(|| usage(&args[0]))),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage(&args[0]),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage(&args[0]));
+
+    let rom = fs::read(rom_path)?;
+    let symbols = match sym_path {
+        Some(path) => Some(SymbolTable::load(path)?),
+        None => None,
+    };
+    let source = RomDisassembly::analyze(&rom, symbols.as_ref()).to_source(rom_path);
+    match output_path {
+        Some(path) => fs::write(path, source)?,
+        None => print!("{}", source),
     }
+    Ok(())
+}
+
+// Compare two Gameboy Doctor traces and report where they first differ
+fn tracediff_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    let mut context = 10;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--context" => {
+                let value = rest.next().unwrap_or_else(|| usage(&args[0]));
+                context = value.parse().unwrap_or_else(|_| usage(&args[0]));
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage(&args[0]);
+    }
+
+    let ours = BufReader::new(File::open(paths[0])?);
+    let reference = BufReader::new(File::open(paths[1])?);
+    match trace_diff::diff(ours, reference, context)? {
+        Some(divergence) => {
+            print!("{}", divergence);
+            std::process::exit(1);
+        }
+        None => println!("Traces match"),
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <path_to_rom>", args[0]);
-        std::process::exit(1);
+    let mut args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("disasm") => return disasm_command(&args),
+        Some("tracediff") => return tracediff_command(&args),
+        _ => (),
     }
 
-    let rom_path = &args[1];
+    // Config file options go first, so the command line overrides them
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        let path = args.get(i + 1).cloned().unwrap_or_else(|| usage(&args[0]));
+        let config = config_args(&path).map_err(|error| format!("{}: {}", path, error))?;
+        args.drain(i..i + 2);
+        args.splice(1..1, config);
+    }
+
+    let mut rom_path = None;
+    let mut trace_path = None;
+    let mut trace_filter = TraceFilter::default();
+    let mut renderer = Renderer::Scanline;
+    let mut colorize = None;  // Some(None) picks the palette from the title
+    let mut palette = DmgPalette::default();
+    let mut correction = ColorCorrection::default();
+    let mut video_filter = VideoFilter::default();
+    let mut screenshot_metadata = false;
+    let mut record_path = None;
+    let mut record_format = VideoFormat::Apng;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().map(String::as_str).unwrap_or_else(|| usage(&args[0]));
+        match arg.as_str() {
+            "--trace" => trace_path = Some(value()),
+            "--trace-pc" => trace_filter.pc_range = Some(trace::parse_pc_range(value()).unwrap_or_else(|| usage(&args[0]))),
+            "--trace-bank" => trace_filter.bank = Some(value().parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--trace-skip" => trace_filter.skip = value().parse().unwrap_or_else(|_| usage(&args[0])),
+            "--trace-count" => trace_filter.limit = Some(value().parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--renderer" => renderer = match value() {
+                "scanline" => Renderer::Scanline,
+                "fifo" => Renderer::Fifo,
+                _ => usage(&args[0]),
+            },
+            "--colorize" => colorize = match value() {
+                "auto" => Some(None),
+                combo => Some(Some(parse_palette_combo(combo).unwrap_or_else(|| usage(&args[0])))),
+            },
+            "--palette" => palette = DmgPalette::parse(value()).unwrap_or_else(|| usage(&args[0])),
+            "--color-correction" => correction = ColorCorrection::parse(value()).unwrap_or_else(|| usage(&args[0])),
+            "--filter" => video_filter.set_filter(Filter::parse(value()).unwrap_or_else(|| usage(&args[0]))),
+            "--ghosting" => video_filter.set_ghosting(true),
+            "--screenshot-metadata" => screenshot_metadata = true,
+            "--record" => record_path = Some(Path::new(value())),
+            "--record-format" => record_format = VideoFormat::parse(value()).unwrap_or_else(|| usage(&args[0])),
+            _ if rom_path.is_none() => rom_path = Some(arg.as_str()),
+            _ => usage(&args[0]),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage(&args[0]));
+
     let mut gameboy = Gameboy::new(rom_path)?;
+    gameboy.memory.ppu_mut().set_renderer(renderer);
+    gameboy.memory.ppu_mut().set_dmg_palette(palette);
+    gameboy.memory.ppu_mut().set_color_correction(correction);
+    if let Some(palette) = colorize {
+        let palette = palette.unwrap_or_else(|| colorize::rom_palette(gameboy.memory.cartridge().rom()));
+        gameboy.set_compat_palette(Some(palette));
+    }
+    if let Some(path) = trace_path {
+        gameboy.cpu.set_tracer(Some(Tracer::create(path, trace_filter)?));
+    }
     println!("First ROM byte: 0x{:02X}", gameboy.memory.read_byte(0x0100));
     println!("Nintendo logo byte: 0x{:02X}", gameboy.memory.read_byte(0x0104));
 
@@ -80,18 +309,88 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     window.limit_update_rate(Some(Duration::from_micros(16600))); // ~60 fps
 
-    let mut frame_count = 0;
+    // --record starts at once; F9 starts and stops clips next to the ROM
+    let mut recorder = None;
+    if let Some(path) = record_path {
+        let format = VideoFormat::from_path(path).unwrap_or_else(|| usage(&args[0]));
+        recorder = start_recording(path, format, video_filter.output_size(WIDTH, HEIGHT));
+    }
+
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+    let mut frame_count: u64 = 0;
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        gameboy.update();
+        for (key, button) in KEYMAP {
+            gameboy.memory.joypad_mut().set_button(button, window.is_key_down(key));
+        }
+        if window.is_key_pressed(FILTER_KEY, KeyRepeat::No) {
+            let current = filter::FILTERS.iter().position(|&filter| filter == video_filter.filter());
+            let next = current.map_or(0, |i| (i + 1) % filter::FILTERS.len());
+            video_filter.set_filter(filter::FILTERS[next]);
+            println!("Filter: {:?}", video_filter.filter());
+        }
+        if window.is_key_pressed(GHOSTING_KEY, KeyRepeat::No) {
+            video_filter.set_ghosting(!video_filter.ghosting());
+            println!("Ghosting: {}", if video_filter.ghosting() { "on" } else { "off" });
+        }
+        if window.is_key_pressed(RECORD_KEY, KeyRepeat::No) {
+            match recorder.take() {
+                Some(active) => stop_recording(active),
+                None => {
+                    let path = capture_path(rom_path, record_format.extension());
+                    recorder = start_recording(&path, record_format, video_filter.output_size(WIDTH, HEIGHT));
+                }
+            }
+        }
+        gameboy.run_frame();
 
-        let buffer: Vec<u32> = gameboy.get_frame_buffer();
-        window.update_with_buffer(&buffer, WIDTH, HEIGHT)?;
+        // The window stretches whatever size the filter produces to fit
+        gameboy.write_frame_xrgb(&mut buffer);
+        let (width, height) = video_filter.output_size(WIDTH, HEIGHT);
+        let frame = video_filter.apply(&buffer, WIDTH, HEIGHT);
+        if window.is_key_pressed(SCREENSHOT_KEY, KeyRepeat::No) {
+            // Saved as shown, with the filter applied
+            let title = gameboy.memory.cartridge().title();
+            let metadata = screenshot_metadata.then_some((title.as_str(), frame_count));
+            match save_screenshot(&Image::from_xrgb(width, height, frame), rom_path, metadata) {
+                Ok(path) => println!("Saved screenshot to {}", path.display()),
+                Err(error) => eprintln!("Couldn't save screenshot: {}", error),
+            }
+        }
+        if let Some(active) = &mut recorder {
+            if active.size() != (width, height) {
+                println!("Filter changed the frame size, stopping the recording");
+                stop_recording(recorder.take().unwrap());
+            } else if let Err(error) = active.write_frame(frame) {
+                eprintln!("Couldn't record frame: {}", error);
+                stop_recording(recorder.take().unwrap());
+            }
+        }
+        window.update_with_buffer(frame, width, height)?;
 
         frame_count += 1;
-        if frame_count % 60 == 0 {
+        if frame_count.is_multiple_of(60) {
             println!("Rendered 60 frames");
         }
     }
+    if let Some(active) = recorder {
+        stop_recording(active);
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_as_utc_dates() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(951782400), "20000229-000000");
+        assert_eq!(format_timestamp(1709164800), "20240229-000000");
+        assert_eq!(format_timestamp(1704067199), "20231231-235959");
+        assert_eq!(format_timestamp(1704067200), "20240101-000000");
+        assert_eq!(format_timestamp(4107542399), "21000228-235959");
+        assert_eq!(format_timestamp(4107542400), "21000301-000000");
+    }
+}
@@ -1,27 +1,70 @@
-use crate::ppu::PPU;
+use crate::apu::APU;
+use crate::cartridge::Cartridge;
+use crate::dma::{Hdma, OamDma};
 use crate::interrupts::InterruptController;
+use crate::joypad::Joypad;
+use crate::peripheral::Peripheral;
+use crate::ppu::PPU;
+use crate::serial::Serial;
+use crate::timer::Timer;
 use std::fs::File;
 use std::io::Read;
 
 pub struct MMU {
     boot_rom: [u8; 256],
-    rom: Vec<u8>,
-    ram: [u8; 8192],
+    cartridge: Cartridge,
+    ram: [u8; 32768],   // 8 banks of 4 KiB, only bank 1 switchable on DMG
     zero_page: [u8; 127],
     in_boot: bool,
+    cgb: bool,
+    svbk: u8,           // WRAM bank at 0xD000, 0 selects bank 1
+    key0: u8,           // CGB mode select, only writable by the boot ROM
+    key1: u8,           // speed switch armed, switched by the next STOP
+    double_speed: bool,
+    undocumented: [u8; 4],  // 0xFF72-0xFF75
     ppu: PPU,
+    timer: Timer,
+    serial: Serial,
+    joypad: Joypad,
+    apu: APU,
+    dma: OamDma,
+    dma_clock: u32,
+    hdma: Hdma,
+    hdma_stall: u32,    // cycles the CPU owes for HDMA blocks copied
+    devices: Vec<Box<dyn Peripheral>>,  // attached by the user, checked first
     interrupt_controller: InterruptController,
 }
 
+impl Default for MMU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MMU {
     pub fn new() -> Self {
         MMU {
             boot_rom: [0; 256],
-            rom: Vec::new(),
-            ram: [0; 8192],
+            cartridge: Cartridge::default(),
+            ram: [0; 32768],
             zero_page: [0; 127],
             in_boot: true,
+            cgb: false,
+            svbk: 0,
+            key0: 0,
+            key1: 0,
+            double_speed: false,
+            undocumented: [0; 4],
             ppu: PPU::new(),
+            timer: Timer::new(),
+            serial: Serial::new(),
+            joypad: Joypad::new(),
+            apu: APU::new(),
+            dma: OamDma::new(),
+            dma_clock: 0,
+            hdma: Hdma::new(),
+            hdma_stall: 0,
+            devices: Vec::new(),
             interrupt_controller: InterruptController::new(),
         }
     }
@@ -34,42 +77,241 @@ impl MMU {
 
     pub fn load_rom(&mut self, filename: &str) -> std::io::Result<()> {
         let mut file = File::open(filename)?;
-        self.rom.clear();
-        file.read_to_end(&mut self.rom)?;
+        let mut rom = Vec::new();
+        file.read_to_end(&mut rom)?;
+        self.load_rom_data(rom);
         Ok(())
     }
 
+    pub fn load_rom_data(&mut self, data: Vec<u8>) {
+        self.cartridge = Cartridge::new(data);
+    }
+
+    // Map a custom device into the address space. It takes precedence over
+    // the built-in hardware for every address it claims.
+    pub fn attach(&mut self, device: Box<dyn Peripheral>) {
+        self.devices.push(device);
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.in_boot
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    // Run as a Game Boy Color. Without it, the CGB registers read 0xFF and
+    // ignore writes, like on a DMG or a CGB in compatibility mode.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb = enabled;
+        self.ppu.set_cgb_mode(enabled);
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    // CGB double speed, where the CPU, timer, serial port and OAM DMA run
+    // at twice the rate of the PPU and APU
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Run by STOP: toggle the speed if KEY1 armed a switch
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || self.key1 & 0x01 == 0 {
+            return false;
+        }
+        self.key1 = 0;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.hdma_stall)
+    }
+
+    // Interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_controller.get_interrupts() & 0x1F
+    }
+
+    // Read as the CPU sees it. While OAM DMA runs, only 0xFF00-0xFFFF
+    // (I/O, HRAM and IE) can be reached; everything else reads 0xFF.
     pub fn read_byte(&self, addr: u16) -> u8 {
+        if self.dma.is_active() && addr < 0xFF00 {
+            return 0xFF;
+        }
+        self.peek(addr)
+    }
+
+    // Read without the DMA restriction, for the DMA itself and for debuggers
+    pub fn peek(&self, addr: u16) -> u8 {
+        if self.in_boot && addr < 0x0100 {
+            return self.boot_rom[addr as usize];
+        }
+        if let Some(device) = self.attached(addr).or_else(|| self.builtin(addr)) {
+            return device.read_byte(addr);
+        }
         match addr {
-            0x0000..=0x00FF if self.in_boot => self.boot_rom[addr as usize],
-            0x0000..=0x7FFF => self.rom[addr as usize],
-            0x8000..=0x9FFF => self.ppu.read_byte(addr),
-            0xA000..=0xBFFF => panic!("Cartridge RAM not implemented"),
-            0xC000..=0xDFFF => self.ram[(addr - 0xC000) as usize],
-            0xE000..=0xFDFF => self.ram[(addr - 0xE000) as usize], // Echo RAM
-            0xFE00..=0xFE9F => self.ppu.read_byte(addr),
+            0xC000..=0xDFFF => self.ram[self.wram_offset(addr)],
+            0xE000..=0xFDFF => self.ram[self.wram_offset(addr - 0x2000)], // Echo RAM
             0xFEA0..=0xFEFF => 0, // Unusable memory
-            0xFF00..=0xFF7F => self.read_io(addr),
+            0xFF0F => self.interrupt_controller.read_byte(addr),
+            0xFF46 => self.dma.read_byte(),
+            0xFF4C..=0xFF77 if self.cgb => self.read_cgb_register(addr),
             0xFF80..=0xFFFE => self.zero_page[(addr - 0xFF80) as usize],
-            0xFFFF => self.interrupt_controller.read_byte(0xFFFF),
+            0xFFFF => self.interrupt_controller.read_byte(addr),
+            _ => 0xFF, // Nothing mapped
         }
     }
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
+        if self.dma.is_active() && addr < 0xFF00 {
+            return;
+        }
+        if let Some(device) = self.attached_mut(addr) {
+            device.write_byte(addr, value);
+            return;
+        }
+        if let Some(device) = self.builtin_mut(addr) {
+            device.write_byte(addr, value);
+            return;
+        }
         match addr {
-            0x0000..=0x7FFF => (), // ROM, read-only
-            0x8000..=0x9FFF => self.ppu.write_byte(addr, value),
-            0xA000..=0xBFFF => panic!("Cartridge RAM not implemented"),
-            0xC000..=0xDFFF => self.ram[(addr - 0xC000) as usize] = value,
-            0xE000..=0xFDFF => self.ram[(addr - 0xE000) as usize] = value, // Echo RAM
-            0xFE00..=0xFE9F => self.ppu.write_byte(addr, value),
+            0xC000..=0xDFFF => self.ram[self.wram_offset(addr)] = value,
+            0xE000..=0xFDFF => self.ram[self.wram_offset(addr - 0x2000)] = value, // Echo RAM
             0xFEA0..=0xFEFF => (), // Unusable memory
-            0xFF00..=0xFF7F => self.write_io(addr, value),
+            0xFF0F => self.interrupt_controller.write_byte(addr, value),
+            0xFF46 => self.dma.write_byte(value),
+            0xFF50 => self.in_boot = false, // Disable boot ROM
+            0xFF4C..=0xFF77 if self.cgb => self.write_cgb_register(addr, value),
             0xFF80..=0xFFFE => self.zero_page[(addr - 0xFF80) as usize] = value,
-            0xFFFF => self.interrupt_controller.write_byte(0xFFFF, value),
+            0xFFFF => self.interrupt_controller.write_byte(addr, value),
+            _ => (), // Nothing mapped
+        }
+    }
+
+    // The built-in device wired to `addr`, if any. A match is much cheaper
+    // than asking every device, so this repeats their ranges(); a test
+    // keeps the two in step.
+    fn builtin(&self, addr: u16) -> Option<&dyn Peripheral> {
+        Some(match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => &self.cartridge,
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => &self.ppu,
+            0xFF00 => &self.joypad,
+            0xFF01..=0xFF02 => &self.serial,
+            0xFF04..=0xFF07 => &self.timer,
+            0xFF10..=0xFF3F => &self.apu,
+            _ => return None,
+        })
+    }
+
+    fn builtin_mut(&mut self, addr: u16) -> Option<&mut dyn Peripheral> {
+        Some(match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => &mut self.cartridge,
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => &mut self.ppu,
+            0xFF00 => &mut self.joypad,
+            0xFF01..=0xFF02 => &mut self.serial,
+            0xFF04..=0xFF07 => &mut self.timer,
+            0xFF10..=0xFF3F => &mut self.apu,
+            _ => return None,
+        })
+    }
+
+    // The user-attached device that answers at `addr`, if any
+    fn attached(&self, addr: u16) -> Option<&dyn Peripheral> {
+        let device = self.devices.iter().find(|device| device.maps(addr))?;
+        Some(device.as_ref())
+    }
+
+    fn attached_mut(&mut self, addr: u16) -> Option<&mut dyn Peripheral> {
+        let device = self.devices.iter_mut().find(|device| device.maps(addr))?;
+        Some(device.as_mut())
+    }
+
+    // Offset into work RAM of 0xC000-0xDFFF, with SVBK picking the bank
+    // at 0xD000
+    fn wram_offset(&self, addr: u16) -> usize {
+        match addr {
+            0xC000..=0xCFFF => (addr - 0xC000) as usize,
+            _ => (self.svbk as usize).max(1) * 0x1000 + (addr - 0xD000) as usize,
+        }
+    }
+
+    // CGB-only I/O that isn't part of the PPU, with unused bits reading 1
+    fn read_cgb_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF4C if self.in_boot => self.key0,
+            0xFF4D => 0x7E | (self.double_speed as u8) << 7 | self.key1,
+            0xFF55 => self.hdma.read_byte(), // HDMA1-4 are write-only
+            0xFF70 => 0xF8 | self.svbk,
+            0xFF72..=0xFF74 => self.undocumented[(addr - 0xFF72) as usize],
+            0xFF75 => 0x8F | self.undocumented[3],
+            0xFF76 | 0xFF77 => 0x00, // PCM amplitudes, silent without sound
+            _ => 0xFF,
+        }
+    }
+
+    fn write_cgb_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF4C if self.in_boot => self.key0 = value & 0x0C,
+            0xFF4D => self.key1 = value & 0x01,
+            0xFF51..=0xFF55 => self.write_hdma(addr, value),
+            0xFF70 => self.svbk = value & 0x07,
+            0xFF72..=0xFF74 => self.undocumented[(addr - 0xFF72) as usize] = value,
+            0xFF75 => self.undocumented[3] = value & 0x70,
+            _ => (),
         }
     }
 
+    // A general-purpose transfer runs to completion as soon as it's started.
+    // An HBlank transfer started during HBlank or with the LCD off copies its
+    // first block right away instead of waiting for the next HBlank.
+    fn write_hdma(&mut self, addr: u16, value: u8) {
+        if self.hdma.write_byte(addr, value) {
+            while self.copy_hdma_block() {}
+        } else if addr == 0xFF55 && self.hdma.is_hblank_active() && self.ppu.mode() == 0 {
+            self.copy_hdma_block();
+        }
+    }
+
+    // Copy the next HDMA block into VRAM. The CPU is held for 8 M-cycles per
+    // block at normal speed and 16 at double speed, paid for through the
+    // stall count.
+    fn copy_hdma_block(&mut self) -> bool {
+        let Some((source, destination)) = self.hdma.next_block() else {
+            return false;
+        };
+        for i in 0..16 {
+            let value = self.peek(source.wrapping_add(i));
+            self.ppu.write_vram(destination + i, value);
+        }
+        self.hdma_stall += if self.double_speed { 64 } else { 32 };
+        true
+    }
+
     pub fn read_word(&self, addr: u16) -> u16 {
         let low = self.read_byte(addr) as u16;
         let high = self.read_byte(addr + 1) as u16;
@@ -83,35 +325,337 @@ impl MMU {
         self.write_byte(addr + 1, high);
     }
 
-    fn read_io(&self, addr: u16) -> u8 {
-        match addr {
-            0xFF00 => panic!("Joypad not implemented"),
-            0xFF01..=0xFF02 => panic!("Serial transfer not implemented"),
-            0xFF04..=0xFF07 => panic!("Timer not implemented"),
-            0xFF0F => self.interrupt_controller.read_byte(addr),
-            0xFF10..=0xFF3F => panic!("Audio not implemented"),
-            0xFF40..=0xFF4B => self.ppu.read_byte(addr),
-            0xFF4C..=0xFF7F => 0, // Unused I/O
-            _ => panic!("Unhandled I/O read at address: {:04X}", addr),
+    pub fn tick(&mut self, cycles: u32) {
+        self.dma_clock += cycles;
+        while self.dma_clock >= 4 {
+            self.dma_clock -= 4;
+            if let Some((source, index)) = self.dma.step() {
+                // Sources from 0xE000 up read the RAM behind echo RAM
+                let source = if source >= 0xE000 { source - 0x2000 } else { source };
+                let value = self.peek(source);
+                self.ppu.write_oam(index, value);
+            }
+        }
+
+        let mut interrupts = 0;
+        let builtin: [&mut dyn Peripheral; 4] = [&mut self.cartridge, &mut self.joypad, &mut self.serial, &mut self.timer];
+        let devices = self.devices.iter_mut().map(|device| device.as_mut());
+        for device in builtin.into_iter().chain(devices) {
+            device.tick(cycles);
+            interrupts |= device.take_interrupts();
+        }
+        // The PPU and APU keep their pace whatever the CPU speed
+        let normal_speed_cycles = if self.double_speed { cycles / 2 } else { cycles };
+        for device in [&mut self.ppu as &mut dyn Peripheral, &mut self.apu] {
+            device.tick(normal_speed_cycles);
+            interrupts |= device.take_interrupts();
+        }
+        if self.ppu.take_hblank() && self.hdma.is_hblank_active() {
+            self.copy_hdma_block();
+        }
+        if interrupts != 0 {
+            self.interrupt_controller.request_interrupt(interrupts);
         }
     }
+}
 
-    fn write_io(&mut self, addr: u16, value: u8) {
-        match addr {
-            0xFF00 => panic!("Joypad not implemented"),
-            0xFF01..=0xFF02 => panic!("Serial transfer not implemented"),
-            0xFF04..=0xFF07 => panic!("Timer not implemented"),
-            0xFF0F => self.interrupt_controller.write_byte(addr, value),
-            0xFF10..=0xFF3F => panic!("Audio not implemented"),
-            0xFF40..=0xFF4B => self.ppu.write_byte(addr, value),
-            0xFF50 => self.in_boot = false, // Disable boot ROM
-            0xFF4C..=0xFF7F => (), // Unused I/O
-            _ => panic!("Unhandled I/O write at address: {:04X}", addr),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dma::OAM_SIZE;
+    use crate::interrupts::TIMER_INTERRUPT;
+    use std::ops::RangeInclusive;
+
+    #[test]
+    fn builtin_routing_matches_device_ranges() {
+        let mmu = MMU::new();
+        let devices: [(&str, &dyn Peripheral); 6] = [
+            ("cartridge", &mmu.cartridge),
+            ("ppu", &mmu.ppu),
+            ("joypad", &mmu.joypad),
+            ("serial", &mmu.serial),
+            ("timer", &mmu.timer),
+            ("apu", &mmu.apu),
+        ];
+        for addr in 0..=0xFFFF {
+            let claimed: Vec<_> = devices.iter().filter(|(_, device)| device.maps(addr)).collect();
+            assert!(claimed.len() <= 1, "{:04X} is claimed more than once", addr);
+            let routed = mmu.builtin(addr).map(|device| device as *const dyn Peripheral as *const ());
+            let expected = claimed.first().map(|&&(_, device)| device as *const dyn Peripheral as *const ());
+            assert_eq!(routed, expected, "{:04X} should go to {:?}", addr, claimed.first().map(|(name, _)| name));
         }
     }
 
-    pub fn tick(&mut self, cycles: u32) {
-        self.ppu.update(cycles);
-        // Update other components here (timer, audio, etc.)
+    // Two registers: a byte of storage and the number of M-cycles ticked.
+    // Raises the timer interrupt every 100 cycles.
+    struct TestDevice {
+        value: u8,
+        cycles: u32,
+        interrupt: bool,
+    }
+
+    impl Peripheral for TestDevice {
+        fn ranges(&self) -> &[RangeInclusive<u16>] {
+            const RANGES: &[RangeInclusive<u16>] = &[0xFF08..=0xFF09];
+            RANGES
+        }
+
+        fn read_byte(&self, addr: u16) -> u8 {
+            match addr {
+                0xFF08 => self.value,
+                _ => (self.cycles / 4) as u8,
+            }
+        }
+
+        fn write_byte(&mut self, addr: u16, value: u8) {
+            if addr == 0xFF08 {
+                self.value = value;
+            }
+        }
+
+        fn tick(&mut self, cycles: u32) {
+            self.interrupt |= (self.cycles + cycles) / 100 > self.cycles / 100;
+            self.cycles += cycles;
+        }
+
+        fn take_interrupts(&mut self) -> u8 {
+            if std::mem::take(&mut self.interrupt) { TIMER_INTERRUPT } else { 0 }
+        }
+    }
+
+    #[test]
+    fn attached_devices_are_read_written_ticked_and_raise_interrupts() {
+        let mut mmu = MMU::new();
+        assert_eq!(mmu.read_byte(0xFF08), 0xFF);
+        mmu.attach(Box::new(TestDevice { value: 0, cycles: 0, interrupt: false }));
+
+        mmu.write_byte(0xFF08, 0x5A);
+        assert_eq!(mmu.read_byte(0xFF08), 0x5A);
+        mmu.write_byte(0xFF09, 0x12);
+        assert_eq!(mmu.read_byte(0xFF09), 0);
+
+        mmu.write_byte(0xFF0F, 0x00);
+        mmu.tick(96);
+        assert_eq!(mmu.read_byte(0xFF09), 24);
+        assert_eq!(mmu.read_byte(0xFF0F) & TIMER_INTERRUPT, 0);
+        mmu.tick(4);
+        assert_eq!(mmu.read_byte(0xFF09), 25);
+        assert_eq!(mmu.read_byte(0xFF0F) & TIMER_INTERRUPT, TIMER_INTERRUPT);
+    }
+
+    // WRAM pages 0xC1 and 0xC2 filled with two different patterns
+    fn dma_mmu() -> MMU {
+        let mut mmu = MMU::new();
+        for i in 0..OAM_SIZE as u16 {
+            mmu.write_byte(0xC100 + i, i as u8);
+            mmu.write_byte(0xC200 + i, 0xFF - i as u8);
+        }
+        mmu
     }
-}
\ No newline at end of file
+
+    fn oam(mmu: &MMU) -> Vec<u8> {
+        (0xFE00..0xFEA0).map(|addr| mmu.peek(addr)).collect()
+    }
+
+    #[test]
+    fn oam_dma_copies_160_bytes_after_a_setup_cycle() {
+        let mut mmu = dma_mmu();
+        mmu.write_byte(0xFF46, 0xC1);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC1);
+        mmu.tick(4);
+        assert!(mmu.dma.is_active());
+        assert_eq!(oam(&mmu), [0; 160]);
+
+        for _ in 0..OAM_SIZE - 1 {
+            mmu.tick(4);
+        }
+        assert!(mmu.dma.is_active());
+        assert_eq!(oam(&mmu)[158..], [158, 0]);
+        mmu.tick(4);
+        assert!(!mmu.dma.is_active());
+        assert_eq!(oam(&mmu), (0..OAM_SIZE).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cpu_only_reaches_high_memory_during_oam_dma() {
+        let mut mmu = dma_mmu();
+        mmu.write_byte(0xFF80, 0x12);
+        mmu.write_byte(0xFF46, 0xC1);
+        mmu.tick(4);
+
+        assert_eq!(mmu.read_byte(0xC105), 0xFF);
+        assert_eq!(mmu.read_byte(0x0100), 0xFF);
+        mmu.write_byte(0xC000, 0x34);
+        assert_eq!(mmu.read_byte(0xFF80), 0x12);
+        mmu.write_byte(0xFF81, 0x56);
+        assert_eq!(mmu.read_byte(0xFF81), 0x56);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC1);
+
+        mmu.tick(OAM_SIZE as u32 * 4);
+        assert_eq!(mmu.read_byte(0xC105), 5);
+        assert_eq!(mmu.read_byte(0xC000), 0x00);
+    }
+
+    #[test]
+    fn restarted_oam_dma_takes_over_after_its_setup() {
+        let mut mmu = dma_mmu();
+        mmu.write_byte(0xFF46, 0xC1);
+        mmu.tick(4 + 50 * 4);
+        mmu.write_byte(0xFF46, 0xC2);
+
+        // The old transfer copies one more byte during the new one's setup
+        mmu.tick(4);
+        assert!(mmu.dma.is_active());
+        assert_eq!(oam(&mmu)[..52], (0..51).chain([0]).collect::<Vec<_>>());
+
+        mmu.tick(4);
+        assert_eq!(oam(&mmu)[0], 0xFF);
+        assert_eq!(oam(&mmu)[51], 0);
+        mmu.tick((OAM_SIZE as u32 - 1) * 4);
+        assert!(!mmu.dma.is_active());
+        assert_eq!(oam(&mmu), (0..OAM_SIZE).map(|i| 0xFF - i).collect::<Vec<_>>());
+    }
+
+    // A CGB with an HDMA source of 0xC000 filled with 0xFF, 0xFE, ... and
+    // the destination at the start of VRAM
+    fn hdma_mmu() -> MMU {
+        let mut mmu = MMU::new();
+        mmu.set_cgb_mode(true);
+        for i in 0..0x100 {
+            mmu.write_byte(0xC000 + i, !(i as u8));
+        }
+        for (addr, value) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00)] {
+            mmu.write_byte(addr, value);
+        }
+        mmu
+    }
+
+    // Bytes copied into VRAM so far, assuming the source pattern above.
+    // VRAM has to be readable, so not in mode 3.
+    fn hdma_copied(mmu: &MMU) -> usize {
+        (0..0x100).take_while(|&i| mmu.peek(0x8000 + i) == !(i as u8)).count()
+    }
+
+    // Tick until the PPU next enters `mode`
+    fn next_mode(mmu: &mut MMU, mode: u8) {
+        while mmu.ppu.mode() == mode {
+            mmu.tick(4);
+        }
+        while mmu.ppu.mode() != mode {
+            mmu.tick(4);
+        }
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_everything_at_once() {
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF55, 0x03);
+        assert_eq!(hdma_copied(&mmu), 64);
+        assert_eq!(mmu.read_byte(0xFF55), 0xFF);
+        assert_eq!(mmu.take_stall_cycles(), 4 * 32);
+        assert_eq!(mmu.take_stall_cycles(), 0);
+    }
+
+    #[test]
+    fn hblank_dma_copies_a_block_per_hblank() {
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF40, 0x80);
+        assert_eq!(mmu.ppu.mode(), 2);
+        mmu.write_byte(0xFF55, 0x82);
+        assert_eq!(hdma_copied(&mmu), 0);
+        assert_eq!(mmu.read_byte(0xFF55), 0x02);
+
+        for (copied, remaining) in [(16, 0x01), (32, 0x00), (48, 0xFF)] {
+            next_mode(&mut mmu, 0);
+            assert_eq!(hdma_copied(&mmu), copied);
+            assert_eq!(mmu.read_byte(0xFF55), remaining);
+            assert_eq!(mmu.take_stall_cycles(), 32);
+        }
+        next_mode(&mut mmu, 0);
+        assert_eq!(hdma_copied(&mmu), 48);
+        assert_eq!(mmu.take_stall_cycles(), 0);
+    }
+
+    #[test]
+    fn cancelled_hblank_dma_keeps_its_remaining_length() {
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF40, 0x80);
+        mmu.write_byte(0xFF55, 0x83);
+        next_mode(&mut mmu, 0);
+        assert_eq!(mmu.read_byte(0xFF55), 0x02);
+
+        mmu.write_byte(0xFF55, 0x00);
+        assert_eq!(mmu.read_byte(0xFF55), 0x82);
+        next_mode(&mut mmu, 0);
+        assert_eq!(hdma_copied(&mmu), 16);
+        assert_eq!(mmu.read_byte(0xFF55), 0x82);
+    }
+
+    #[test]
+    fn hblank_dma_started_in_hblank_or_with_the_lcd_off_copies_at_once() {
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF55, 0x81);
+        assert_eq!(hdma_copied(&mmu), 16);
+        assert_eq!(mmu.read_byte(0xFF55), 0x00);
+
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF40, 0x80);
+        next_mode(&mut mmu, 0);
+        mmu.write_byte(0xFF55, 0x81);
+        assert_eq!(hdma_copied(&mmu), 16);
+        // And the next block still waits for the next HBlank
+        next_mode(&mut mmu, 2);
+        assert_eq!(hdma_copied(&mmu), 16);
+        next_mode(&mut mmu, 0);
+        assert_eq!(hdma_copied(&mmu), 32);
+    }
+
+    #[test]
+    fn hdma_stall_doubles_in_double_speed() {
+        let mut mmu = hdma_mmu();
+        mmu.write_byte(0xFF4D, 0x01);
+        assert!(mmu.switch_speed());
+        mmu.write_byte(0xFF55, 0x01);
+        assert_eq!(hdma_copied(&mmu), 32);
+        assert_eq!(mmu.take_stall_cycles(), 2 * 64);
+    }
+
+    #[test]
+    fn svbk_switches_the_wram_bank_at_0xd000() {
+        let mut mmu = MMU::new();
+        mmu.set_cgb_mode(true);
+        mmu.write_byte(0xC000, 0xC0);
+        for bank in 1..8 {
+            mmu.write_byte(0xFF70, bank);
+            assert_eq!(mmu.read_byte(0xFF70), 0xF8 | bank);
+            mmu.write_byte(0xD000, bank * 0x11);
+        }
+        for bank in 1..8 {
+            mmu.write_byte(0xFF70, bank);
+            assert_eq!(mmu.read_byte(0xD000), bank * 0x11, "bank {}", bank);
+            assert_eq!(mmu.read_byte(0xF000), bank * 0x11, "echo of bank {}", bank);
+            assert_eq!(mmu.read_byte(0xC000), 0xC0);
+        }
+
+        // Bank 0 can't be mapped at 0xD000, writing 0 selects bank 1
+        mmu.write_byte(0xFF70, 0);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF8);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+        mmu.write_byte(0xFF70, 0xFA);
+        assert_eq!(mmu.read_byte(0xFF70), 0xFA);
+        assert_eq!(mmu.read_byte(0xD000), 0x22);
+    }
+
+    #[test]
+    fn svbk_is_ignored_on_dmg() {
+        let mut mmu = MMU::new();
+        mmu.write_byte(0xD000, 0x11);
+        mmu.write_byte(0xFF70, 2);
+        assert_eq!(mmu.read_byte(0xFF70), 0xFF);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+        mmu.write_byte(0xD000, 0x22);
+        mmu.set_cgb_mode(true);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF8);
+        assert_eq!(mmu.read_byte(0xD000), 0x22);
+    }
+}
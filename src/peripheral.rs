@@ -0,0 +1,26 @@
+use std::ops::RangeInclusive;
+
+// A device on the memory bus. The MMU routes every access inside one of
+// the device's address ranges to it, advances it with the clock and
+// collects the interrupts it raises. Devices registered with
+// MMU::attach are checked before the built-in hardware, so they can also
+// claim unused I/O addresses (debug ports, test devices).
+pub trait Peripheral {
+    // Addresses the device responds to
+    fn ranges(&self) -> &[RangeInclusive<u16>];
+
+    fn read_byte(&self, addr: u16) -> u8;
+    fn write_byte(&mut self, addr: u16, value: u8);
+
+    // Advance the device by `cycles` clock cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    // IF bits raised since the last call
+    fn take_interrupts(&mut self) -> u8 {
+        0
+    }
+
+    fn maps(&self, addr: u16) -> bool {
+        self.ranges().iter().any(|range| range.contains(&addr))
+    }
+}
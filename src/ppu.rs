@@ -1,7 +1,101 @@
-use crate::memory::MMU;
+use crate::color::{ColorConverter, ColorCorrection, DmgPalette, PixelFormat};
+use crate::colorize::CompatPalette;
+use crate::interrupts::{LCD_STAT_INTERRUPT, VBLANK_INTERRUPT};
+use crate::peripheral::Peripheral;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+const WHITE: u16 = 0x7FFF;
+
+// How mode 3 turns VRAM into pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    // Whole line at once at the end of mode 3. Fast, but blind to register
+    // writes made during the line.
+    Scanline,
+    // Dot by dot through the background and OBJ pixel FIFOs, so mid-line
+    // changes to SCX, palettes or LCDC land where they do on hardware and
+    // mode 3 takes as long as sprites and the window make it.
+    Fifo,
+}
+
+// The screen as the PPU drew it, before any palette or format conversion
+pub enum RawFrame<'a> {
+    Shades(&'a [u8; 160 * 144]),     // DMG shades 0-3
+    Rgb555(&'a [u16; 160 * 144]),    // CGB games and colorized DMG games
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    palette: u8,      // CGB palette number
+    priority: bool,   // CGB map attribute: drawn over OBJs
+}
+
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    palette: u8,      // OBP0/OBP1 on DMG, palette number on CGB
+    behind_bg: bool,
+    oam_index: u8,    // decides priority between OBJs on CGB
+}
+
+// State of the pixel pipeline during one line of mode 3
+struct Fifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    step: FetchStep,
+    step_dots: u8,
+    fetch_x: u8,                    // tile column being fetched
+    tile: u8,
+    attributes: u8,                 // CGB map attributes of that tile
+    low: u8,
+    high: u8,
+    first_fetch: bool,              // the first tile of a line is fetched twice
+    window: bool,                   // fetching from the window map
+    window_y_hit: bool,             // LY has matched WY this frame
+    discard: u8,                    // pixels still to drop for SCX & 7
+    lcd_x: u8,                      // next pixel on screen
+    dots: u32,                      // length of mode 3 so far
+    sprites: Vec<usize>,            // OBJs on this line not fetched yet
+    sprite_fetch: Option<(usize, u8)>,  // OBJ being fetched and dots left
+}
+
+impl Fifo {
+    fn new() -> Self {
+        Fifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile: 0,
+            attributes: 0,
+            low: 0,
+            high: 0,
+            first_fetch: true,
+            window: false,
+            window_y_hit: false,
+            discard: 0,
+            lcd_x: 0,
+            dots: 0,
+            sprites: Vec::with_capacity(10),
+            sprite_fetch: None,
+        }
+    }
+}
 
 pub struct PPU {
-    vram: [u8; 8192],
+    vram: [u8; 16384],  // two 8 KiB banks, the second one CGB only
+    vram_bank: usize,
     oam: [u8; 160],
     lcd_control: u8,
     lcd_status: u8,
@@ -14,19 +108,40 @@ pub struct PPU {
     obj_palette1: u8,
     window_y: u8,
     window_x: u8,
-    pub framebuffer: [u8; 160 * 144],
+    bg_palette_ram: [u8; 64],   // CGB: 8 palettes of 4 RGB555 colors
+    obj_palette_ram: [u8; 64],
+    bg_palette_index: u8,       // BCPS, bit 7 is auto-increment
+    obj_palette_index: u8,      // OCPS
+    obj_priority: u8,           // OPRI, bit 0 set orders OBJs by X like a DMG
+    cgb: bool,
+    colorize: bool,             // DMG shades go through CGB palettes 0 and 1
+    window_line: u8,  // window row to draw next, reset every frame
+    stat_line: bool,  // OR of the enabled STAT interrupt sources
+    interrupts: u8,   // IF bits raised since the MMU last collected them
+    pub framebuffer: [u8; 160 * 144],         // DMG shades, or color numbers on CGB
+    pub color_framebuffer: [u16; 160 * 144],  // RGB555, CGB only
     mode_clock: u32,
     current_mode: u8,
-    dma_active: bool,
-    dma_byte: u8,
-    dma_start_delay: u8,
-    dma_source: u16,
+    renderer: Renderer,
+    colors: ColorConverter,  // for get_frame_buffer
+    fifo: Fifo,
+    hblank_length: u32,  // 376 dots minus however long mode 3 took
+    blank_frame: bool,   // first frame after the LCD was switched on
+    hblank_started: bool,  // mode 0 began since the MMU last checked, for HDMA
+    frame_complete: bool,  // VBlank began since the last take_frame_complete
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PPU {
     pub fn new() -> Self {
         PPU {
-            vram: [0; 8192],
+            vram: [0; 16384],
+            vram_bank: 0,
             oam: [0; 160],
             lcd_control: 0,
             lcd_status: 0,
@@ -39,105 +154,192 @@ impl PPU {
             obj_palette1: 0,
             window_y: 0,
             window_x: 0,
+            bg_palette_ram: [0xFF; 64],
+            obj_palette_ram: [0xFF; 64],
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+            obj_priority: 0,
+            cgb: false,
+            colorize: false,
+            window_line: 0,
+            stat_line: false,
+            interrupts: 0,
             framebuffer: [0; 160 * 144],
+            color_framebuffer: [WHITE; 160 * 144],
             mode_clock: 0,
-            current_mode: 2,
-            dma_active: false,
-            dma_byte: 0,
-            dma_start_delay: 0,
-            dma_source: 0,
+            current_mode: 0,
+            renderer: Renderer::Scanline,
+            colors: ColorConverter::new(),
+            fifo: Fifo::new(),
+            hblank_length: 204,
+            blank_frame: false,
+            hblank_started: false,
+            frame_complete: false,
         }
     }
 
-    pub fn update(&mut self, cycles: u32) {
-        if !self.is_lcd_enabled() {
-            return;
-        }
+    // OAM DMA writes straight into OAM
+    pub fn write_oam(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
 
-        self.mode_clock += cycles;
+    // HDMA writes into the selected VRAM bank regardless of the mode
+    pub fn write_vram(&mut self, offset: u16, value: u8) {
+        self.vram[self.vram_bank * 0x2000 + (offset & 0x1FFF) as usize] = value;
+    }
 
-        match self.current_mode {
-            2 => self.handle_oam_scan(),
-            3 => self.handle_pixel_transfer(),
-            0 => self.handle_hblank(),
-            1 => self.handle_vblank(),
-            _ => unreachable!(),
-        }
+    // Whether an HBlank started since the last call
+    pub fn take_hblank(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
 
-        if self.dma_active {
-            self.update_dma(cycles);
-        }
+    // Whether a whole frame was drawn since the last call
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
 
-        for y in 0..144 {
-            for x in 0..160 {
-                let color = ((x + y) % 4) as u8;
-                self.framebuffer[y * 160 + x] = color;
-            }
-        }
-        println!("PPU updated");
+    // STAT mode: 0 HBlank, 1 VBlank, 2 OAM scan, 3 drawing. Always 0 while
+    // the LCD is off.
+    pub fn mode(&self) -> u8 {
+        self.current_mode
     }
 
-    fn update_dma(&mut self, mut cycles: u32) {
-        if self.dma_start_delay > 0 {
-            if cycles >= self.dma_start_delay as u32 {
-                cycles -= self.dma_start_delay as u32;
-                self.dma_start_delay = 0;
-            } else {
-                self.dma_start_delay -= cycles as u8;
-                return;
-            }
-        }
+    pub fn is_lcd_enabled(&self) -> bool {
+        self.lcd_control & 0x80 != 0
+    }
 
-        let bytes_to_transfer = cycles.min(160 - self.dma_byte as u32);
-        self.dma_byte += bytes_to_transfer as u8;
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
 
-        if self.dma_byte >= 160 {
-            self.dma_active = false;
-        }
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
     }
 
-    pub fn dma_read(&self, addr: u16) -> u8 {
-        // This method should be called by MMU during DMA
-        self.oam[addr as usize]
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
     }
 
-    pub fn dma_write(&mut self, addr: u16, value: u8) {
-        // This method should be called by MMU during DMA
-        self.oam[addr as usize] = value;
+    // Run as a Game Boy Color: VRAM bank 1, color palettes, BG map
+    // attributes and RGB output
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb = enabled;
     }
 
-    fn is_lcd_enabled(&self) -> bool {
-        self.lcd_control & 0x80 != 0
+    // Color DMG output the way a CGB running a DMG game does: BGP, OBP0 and
+    // OBP1 pick shades as usual, which then index CGB palettes loaded with
+    // `palette`. None goes back to plain shades.
+    pub fn set_compat_palette(&mut self, palette: Option<&CompatPalette>) {
+        self.colorize = palette.is_some();
+        if let Some(palette) = palette {
+            Self::load_palette(&mut self.bg_palette_ram, 0, &palette.bg);
+            Self::load_palette(&mut self.obj_palette_ram, 0, &palette.obj0);
+            Self::load_palette(&mut self.obj_palette_ram, 1, &palette.obj1);
+        }
+    }
+
+    fn load_palette(ram: &mut [u8; 64], palette: usize, colors: &[u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            ram[palette * 8 + i * 2..][..2].copy_from_slice(&color.to_le_bytes());
+        }
     }
 
     fn handle_oam_scan(&mut self) {
         if self.mode_clock >= 80 {
-            self.mode_clock = 0;
-            self.current_mode = 3;
+            self.mode_clock -= 80;
             self.set_mode(3);
+            if self.renderer == Renderer::Fifo {
+                self.start_fifo_line();
+            }
         }
     }
 
     fn handle_pixel_transfer(&mut self) {
-        if self.mode_clock >= 172 {
-            self.mode_clock = 0;
-            self.current_mode = 0;
-            self.set_mode(0);
-            self.render_scan_line();
+        match self.renderer {
+            Renderer::Scanline => {
+                if self.mode_clock >= 172 {
+                    self.mode_clock -= 172;
+                    self.hblank_length = 204;
+                    self.hblank_started = true;
+                    self.set_mode(0);
+                    self.render_scan_line();
+                    self.blank_line_if_needed();
+                }
+            }
+            Renderer::Fifo => {
+                while self.mode_clock > 0 && self.fifo.lcd_x < 160 {
+                    self.mode_clock -= 1;
+                    self.fifo_dot();
+                }
+                if self.fifo.lcd_x == 160 {
+                    if self.fifo.window {
+                        self.window_line += 1;
+                    }
+                    self.hblank_length = 376u32.saturating_sub(self.fifo.dots);
+                    self.hblank_started = true;
+                    self.set_mode(0);
+                    self.blank_line_if_needed();
+                }
+            }
+        }
+    }
+
+    // The first frame after the LCD is switched on is not shown
+    fn blank_line_if_needed(&mut self) {
+        if self.blank_frame {
+            let line = self.ly as usize;
+            self.framebuffer[line * 160..(line + 1) * 160].fill(0);
+            self.color_framebuffer[line * 160..(line + 1) * 160].fill(WHITE);
+        }
+    }
+
+    fn set_lcd_control(&mut self, value: u8) {
+        let was_enabled = self.is_lcd_enabled();
+        self.lcd_control = value;
+        match (was_enabled, self.is_lcd_enabled()) {
+            (true, false) => {
+                // LY and the mode stay at 0 while the LCD is off
+                self.ly = 0;
+                self.mode_clock = 0;
+                self.current_mode = 0;
+                self.lcd_status &= !0x03;
+                self.stat_line = false;
+                self.framebuffer.fill(0);
+                self.color_framebuffer.fill(WHITE);
+            }
+            (false, true) => {
+                self.blank_frame = true;
+                self.window_line = 0;
+                self.fifo.window_y_hit = false;
+                self.set_mode(2);
+            }
+            _ => (),
         }
     }
 
+    // VRAM is locked during mode 3 and OAM during modes 2 and 3, unless
+    // the LCD is off
+    fn vram_accessible(&self) -> bool {
+        !self.is_lcd_enabled() || self.current_mode != 3
+    }
+
+    fn oam_accessible(&self) -> bool {
+        !self.is_lcd_enabled() || self.current_mode < 2
+    }
+
     fn handle_hblank(&mut self) {
-        if self.mode_clock >= 204 {
-            self.mode_clock = 0;
+        if self.mode_clock >= self.hblank_length {
+            self.mode_clock -= self.hblank_length;
             self.ly += 1;
 
             if self.ly == 144 {
-                self.current_mode = 1;
+                self.blank_frame = false;
+                self.window_line = 0;
+                self.fifo.window_y_hit = false;
+                self.interrupts |= VBLANK_INTERRUPT;
+                self.frame_complete = true;
                 self.set_mode(1);
-                // Request VBlank interrupt
             } else {
-                self.current_mode = 2;
                 self.set_mode(2);
             }
         }
@@ -145,79 +347,477 @@ impl PPU {
 
     fn handle_vblank(&mut self) {
         if self.mode_clock >= 456 {
-            self.mode_clock = 0;
+            self.mode_clock -= 456;
             self.ly += 1;
 
             if self.ly > 153 {
                 self.ly = 0;
-                self.current_mode = 2;
                 self.set_mode(2);
+            } else {
+                self.update_stat();
             }
         }
     }
 
     fn set_mode(&mut self, mode: u8) {
+        self.current_mode = mode;
         self.lcd_status = (self.lcd_status & 0xFC) | mode;
+        self.update_stat();
+    }
+
+    // Refresh the LY=LYC flag and raise the STAT interrupt when the OR of
+    // all enabled sources goes from low to high. While the line stays high,
+    // further sources becoming active don't trigger again ("STAT blocking").
+    fn update_stat(&mut self) {
+        if self.ly == self.ly_compare {
+            self.lcd_status |= 0x04;
+        } else {
+            self.lcd_status &= !0x04;
+        }
+
+        let status = self.lcd_status;
+        let line = (status & 0x08 != 0 && self.current_mode == 0)
+            || (status & 0x10 != 0 && self.current_mode == 1)
+            || (status & 0x20 != 0 && self.current_mode == 2)
+            || (status & 0x40 != 0 && status & 0x04 != 0);
+        if line && !self.stat_line {
+            self.interrupts |= LCD_STAT_INTERRUPT;
+        }
+        self.stat_line = line;
+    }
+
+    fn start_fifo_line(&mut self) {
+        let window_y_hit = self.fifo.window_y_hit || self.ly == self.window_y;
+        let sprites = if self.lcd_control & 0x02 != 0 { self.sprites_on_line(self.ly) } else { Vec::new() };
+        self.fifo = Fifo {
+            window_y_hit,
+            discard: self.scroll_x & 7,
+            sprites,
+            ..Fifo::new()
+        };
+    }
+
+    // One dot of mode 3
+    fn fifo_dot(&mut self) {
+        self.fifo.dots += 1;
+
+        // OBJ fetches stall both the background fetcher and the pixel output
+        if let Some((sprite, dots)) = self.fifo.sprite_fetch {
+            if dots > 1 {
+                self.fifo.sprite_fetch = Some((sprite, dots - 1));
+            } else {
+                self.fifo.sprite_fetch = None;
+                self.merge_sprite(sprite);
+            }
+            return;
+        }
+
+        let lcd_x = self.fifo.lcd_x as i16;
+        let hit = self.fifo.sprites.iter()
+            .position(|&i| self.oam[i * 4 + 1] as i16 - 8 <= lcd_x);
+        if let Some(position) = hit.filter(|_| self.lcd_control & 0x02 != 0 && self.fifo.discard == 0) {
+            // Wait for the background fetch in progress to finish first
+            if self.fifo.step == FetchStep::Push && !self.fifo.bg.is_empty() {
+                let sprite = self.fifo.sprites.remove(position);
+                self.fifo.sprite_fetch = Some((sprite, 6));
+            } else {
+                self.fetcher_dot();
+            }
+            return;
+        }
+
+        self.fetcher_dot();
+        self.check_window_trigger();
+        self.output_pixel();
+    }
+
+    fn check_window_trigger(&mut self) {
+        let fifo = &self.fifo;
+        if fifo.window || self.lcd_control & 0x20 == 0 || !fifo.window_y_hit {
+            return;
+        }
+        if fifo.lcd_x as u16 + 7 < self.window_x as u16 || self.window_x > 166 {
+            return;
+        }
+        // Restart the fetcher on the window map
+        let fifo = &mut self.fifo;
+        fifo.window = true;
+        fifo.bg.clear();
+        fifo.step = FetchStep::Tile;
+        fifo.step_dots = 0;
+        fifo.fetch_x = 0;
+        // A window covering the left edge replaces the SCX & 7 discard with
+        // its own, so WX = 7 shows the window unshifted
+        if fifo.lcd_x == 0 {
+            fifo.discard = 7u8.saturating_sub(self.window_x);
+        }
+    }
+
+    fn fetcher_dot(&mut self) {
+        if self.fifo.step != FetchStep::Push {
+            self.fifo.step_dots += 1;
+            if self.fifo.step_dots < 2 {
+                return;
+            }
+            self.fifo.step_dots = 0;
+        }
+
+        let (high_map, column, y) = if self.fifo.window {
+            (self.lcd_control & 0x40 != 0, self.fifo.fetch_x, self.window_line)
+        } else {
+            let column = (self.scroll_x / 8).wrapping_add(self.fifo.fetch_x);
+            (self.lcd_control & 0x08 != 0, column, self.ly.wrapping_add(self.scroll_y))
+        };
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                (self.fifo.tile, self.fifo.attributes) = self.map_entry(high_map, column, y);
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.fifo.low = self.tile_row(self.fifo.tile, self.fifo.attributes, y).0;
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                self.fifo.high = self.tile_row(self.fifo.tile, self.fifo.attributes, y).1;
+                self.fifo.step = FetchStep::Push;
+                self.push_tile();
+            }
+            FetchStep::Push => self.push_tile(),
+        }
+    }
+
+    // Push the fetched tile row once the background FIFO has drained
+    fn push_tile(&mut self) {
+        let fifo = &mut self.fifo;
+        if !fifo.bg.is_empty() {
+            return;
+        }
+        fifo.step = FetchStep::Tile;
+        if fifo.first_fetch {
+            fifo.first_fetch = false;
+            return;
+        }
+        for column in 0..8 {
+            let bit = if fifo.attributes & 0x20 != 0 { column } else { 7 - column };
+            fifo.bg.push_back(BgPixel {
+                color: ((fifo.high >> bit) & 1) << 1 | ((fifo.low >> bit) & 1),
+                palette: fifo.attributes & 0x07,
+                priority: fifo.attributes & 0x80 != 0,
+            });
+        }
+        fifo.fetch_x = fifo.fetch_x.wrapping_add(1);
+    }
+
+    // Mix OBJ `i` into the OBJ FIFO. On DMG it only fills transparent slots
+    // because OBJs fetched earlier have priority; on CGB the lower OAM index
+    // wins instead.
+    fn merge_sprite(&mut self, i: usize) {
+        let x = self.oam[i * 4 + 1] as i16 - 8;
+        let skip = (self.fifo.lcd_x as i16 - x).max(0) as usize;
+        let by_oam_index = self.priority_by_oam_index();
+
+        for (column, pixel) in self.sprite_pixels(i, self.ly).into_iter().enumerate().skip(skip) {
+            match self.fifo.obj.get_mut(column - skip) {
+                Some(slot) if slot.color == 0 || (by_oam_index && pixel.color != 0 && pixel.oam_index < slot.oam_index) => {
+                    *slot = pixel
+                }
+                Some(_) => (),
+                None => self.fifo.obj.push_back(pixel),
+            }
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let Some(bg) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let obj = self.fifo.obj.pop_front();
+        self.put_pixel(self.fifo.lcd_x as usize, bg, obj);
+        self.fifo.lcd_x += 1;
+    }
+
+    // Decide between the background and OBJ pixel at `x` on the current line
+    // and store the result
+    fn put_pixel(&mut self, x: usize, mut bg: BgPixel, obj: Option<ObjPixel>) {
+        // LCDC bit 0 blanks the background on DMG, but on CGB it only takes
+        // away the background's priority over OBJs
+        let master_priority = self.lcd_control & 0x01 != 0;
+        if !self.cgb && !master_priority {
+            bg.color = 0;
+        }
+        let obj = obj.filter(|obj| {
+            obj.color != 0
+                && self.lcd_control & 0x02 != 0
+                && (bg.color == 0 || !master_priority && self.cgb || !(obj.behind_bg || bg.priority))
+        });
+
+        let index = self.ly as usize * 160 + x;
+        match obj {
+            Some(obj) if self.cgb => {
+                self.framebuffer[index] = obj.color;
+                self.color_framebuffer[index] = Self::palette_color(&self.obj_palette_ram, obj.palette, obj.color);
+            }
+            Some(obj) => {
+                let palette = if obj.palette == 1 { self.obj_palette1 } else { self.obj_palette0 };
+                let shade = Self::apply_palette(palette, obj.color);
+                self.framebuffer[index] = shade;
+                if self.colorize {
+                    self.color_framebuffer[index] = Self::palette_color(&self.obj_palette_ram, obj.palette, shade);
+                }
+            }
+            None if self.cgb => {
+                self.framebuffer[index] = bg.color;
+                self.color_framebuffer[index] = Self::palette_color(&self.bg_palette_ram, bg.palette, bg.color);
+            }
+            None => {
+                let shade = Self::apply_palette(self.bg_palette, bg.color);
+                self.framebuffer[index] = shade;
+                if self.colorize {
+                    self.color_framebuffer[index] = Self::palette_color(&self.bg_palette_ram, 0, shade);
+                }
+            }
+        }
     }
 
     fn render_scan_line(&mut self) {
-        let line = self.ly as usize;
-        for x in 0..160 {
-            let color = self.get_background_pixel(x, line);
-            self.framebuffer[line * 160 + x] = color;
+        // Background and window pixels, kept whole for the OBJ priority check
+        let mut bg = [BgPixel::default(); 160];
+
+        if self.cgb || self.lcd_control & 0x01 != 0 {
+            let y = self.ly.wrapping_add(self.scroll_y);
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                let x = (x as u8).wrapping_add(self.scroll_x);
+                *pixel = self.map_pixel(self.lcd_control & 0x08 != 0, x, y);
+            }
+
+            // The window has its own line counter that only advances on lines
+            // where it was actually drawn
+            let window_left = self.window_x as i16 - 7;
+            if self.lcd_control & 0x20 != 0 && self.ly >= self.window_y && window_left < 160 {
+                let y = self.window_line;
+                for (x, pixel) in bg.iter_mut().enumerate().skip(window_left.max(0) as usize) {
+                    let x = (x as i16 - window_left) as u8;
+                    *pixel = self.map_pixel(self.lcd_control & 0x40 != 0, x, y);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let objs = if self.lcd_control & 0x02 != 0 { self.line_sprites(self.ly) } else { [None; 160] };
+        for (x, (&bg, &obj)) in bg.iter().zip(&objs).enumerate() {
+            self.put_pixel(x, bg, obj);
         }
     }
 
-    pub fn get_frame_buffer(&self) -> Vec<u32> {
-        self.framebuffer.iter().map(|&color| {
-            match color {
-                0 => 0xFFFFFFFF, // White
-                1 => 0xAAAAAAFF, // Light gray
-                2 => 0x555555FF, // Dark gray
-                3 => 0x000000FF, // Black
-                _ => 0xFF0000FF, // Red (shouldn't happen)
+    // The visible OBJ pixel at each position of `line`. On DMG the OBJ with
+    // the smallest X (then the lowest OAM index) is on top, on CGB the one
+    // with the lowest OAM index.
+    fn line_sprites(&self, line: u8) -> [Option<ObjPixel>; 160] {
+        let mut sprites = self.sprites_on_line(line);
+        if !self.priority_by_oam_index() {
+            sprites.sort_by_key(|&i| self.oam[i * 4 + 1]);
+        }
+
+        let mut pixels = [None; 160];
+        for &i in &sprites {
+            let x = self.oam[i * 4 + 1] as i16 - 8;
+            for (column, pixel) in self.sprite_pixels(i, line).into_iter().enumerate() {
+                let screen_x = x + column as i16;
+                if pixel.color != 0 && (0..160).contains(&screen_x) {
+                    pixels[screen_x as usize].get_or_insert(pixel);
+                }
+            }
+        }
+        pixels
+    }
+
+    // The eight pixels of OBJ `i` on `line`, left to right
+    fn sprite_pixels(&self, i: usize, line: u8) -> [ObjPixel; 8] {
+        let flags = self.oam[i * 4 + 3];
+        let (low, high) = self.sprite_row(i, line);
+        let palette = if self.cgb { flags & 0x07 } else { (flags >> 4) & 1 };
+        std::array::from_fn(|column| {
+            let bit = if flags & 0x20 != 0 { column } else { 7 - column };
+            ObjPixel {
+                color: ((high >> bit) & 1) << 1 | ((low >> bit) & 1),
+                palette,
+                behind_bg: flags & 0x80 != 0,
+                oam_index: i as u8,
             }
-        }).collect()
+        })
     }
 
-    fn get_background_pixel(&self, x: usize, y: usize) -> u8 {
-        let tile_map = if self.lcd_control & 0x08 != 0 { 0x9C00 } else { 0x9800 };
-        let tile_data = if self.lcd_control & 0x10 != 0 { 0x8000 } else { 0x8800 };
+    // CGB orders overlapping OBJs by OAM index unless OPRI asks for the DMG
+    // behavior
+    fn priority_by_oam_index(&self) -> bool {
+        self.cgb && self.obj_priority & 0x01 == 0
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcd_control & 0x04 != 0 { 16 } else { 8 }
+    }
+
+    // OAM indices of the first ten OBJs overlapping `line`, in OAM order
+    fn sprites_on_line(&self, line: u8) -> Vec<usize> {
+        let height = self.sprite_height();
+        (0..40)
+            .filter(|&i| {
+                let top = self.oam[i * 4] as i16 - 16;
+                (top..top + height).contains(&(line as i16))
+            })
+            .take(10)
+            .collect()
+    }
 
-        let scroll_x = self.scroll_x as usize;
-        let scroll_y = self.scroll_y as usize;
+    // The two tile data bytes of OBJ `i` for `line`, with Y flip applied
+    fn sprite_row(&self, i: usize, line: u8) -> (u8, u8) {
+        let height = self.sprite_height();
+        let mut tile = self.oam[i * 4 + 2];
+        let flags = self.oam[i * 4 + 3];
 
-        let adjusted_x = (x + scroll_x) & 255;
-        let adjusted_y = (y + scroll_y) & 255;
+        let mut row = line as i16 - (self.oam[i * 4] as i16 - 16);
+        if flags & 0x40 != 0 {
+            row = height - 1 - row;
+        }
+        if height == 16 {
+            tile &= 0xFE;
+        }
+        let bank = if self.cgb && flags & 0x08 != 0 { 0x2000 } else { 0 };
+        let address = bank + tile as usize * 16 + row as usize * 2;
+        (self.vram[address], self.vram[address + 1])
+    }
 
-        let tile_x = adjusted_x / 8;
-        let tile_y = adjusted_y / 8;
+    fn apply_palette(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0x03
+    }
 
-        let tile_index_addr = tile_map - 0x8000 + tile_y * 32 + tile_x;
-        let tile_index = self.vram[tile_index_addr];
+    // Color `color` of CGB palette `palette`, as stored little-endian in
+    // palette RAM
+    fn palette_color(ram: &[u8; 64], palette: u8, color: u8) -> u16 {
+        let index = palette as usize * 8 + color as usize * 2;
+        u16::from_le_bytes([ram[index], ram[index + 1]]) & 0x7FFF
+    }
 
-        let tile_data_addr = if tile_data == 0x8000 {
-            tile_data - 0x8000 + (tile_index as usize) * 16
+    // BCPS/OCPS with bit 7 set step to the next byte after every data write
+    fn increment_palette_index(index: u8) -> u8 {
+        if index & 0x80 != 0 {
+            0x80 | ((index + 1) & 0x3F)
         } else {
-            (tile_data - 0x8000 as usize).wrapping_add((tile_index as i8 as usize) * 16)
-        };
+            index
+        }
+    }
 
-        let tile_row = (adjusted_y % 8) * 2;
-        let tile_data_low = self.vram[tile_data_addr + tile_row];
-        let tile_data_high = self.vram[tile_data_addr + tile_row + 1];
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.colors.set_dmg_palette(palette);
+    }
 
-        let color_bit = 7 - (adjusted_x % 8);
-        let color_num = ((tile_data_high >> color_bit) & 1) << 1 | ((tile_data_low >> color_bit) & 1);
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.colors.set_correction(correction);
+    }
 
-        (self.bg_palette >> (color_num * 2)) & 0x03
+    pub fn raw_frame(&self) -> RawFrame<'_> {
+        if self.cgb || self.colorize {
+            RawFrame::Rgb555(&self.color_framebuffer)
+        } else {
+            RawFrame::Shades(&self.framebuffer)
+        }
     }
 
-    pub fn read_byte(&self, addr: u16) -> u8 {
+    // Call `f` with every pixel on screen as 0x00RRGGBB, row by row
+    fn for_each_pixel(&self, mut f: impl FnMut(usize, u32)) {
+        match self.raw_frame() {
+            RawFrame::Shades(shades) => {
+                for (i, &shade) in shades.iter().enumerate() {
+                    f(i, self.colors.dmg_color(shade));
+                }
+            }
+            RawFrame::Rgb555(colors) => {
+                for (i, &color) in colors.iter().enumerate() {
+                    f(i, self.colors.cgb_color(color));
+                }
+            }
+        }
+    }
+
+    // Convert the screen into `out`, 160x144 pixels of `format` row by row
+    pub fn write_frame(&self, format: PixelFormat, out: &mut [u8]) {
+        let size = format.bytes_per_pixel();
+        assert_eq!(out.len(), 160 * 144 * size, "frame buffer has the wrong size");
+        self.for_each_pixel(|i, rgb| format.write(rgb, &mut out[i * size..(i + 1) * size]));
+    }
+
+    // The same for frontends like minifb that take 0x00RRGGBB u32s
+    pub fn write_frame_xrgb(&self, out: &mut [u32]) {
+        assert_eq!(out.len(), 160 * 144, "frame buffer has the wrong size");
+        self.for_each_pixel(|i, rgb| out[i] = rgb);
+    }
+
+    // The screen as 0x00RRGGBB pixels in a new buffer
+    pub fn get_frame_buffer(&self) -> Vec<u32> {
+        let mut buffer = vec![0; 160 * 144];
+        self.write_frame_xrgb(&mut buffer);
+        buffer
+    }
+
+    // Pixel at (x, y) of the 256x256 background map selected by `high_map`
+    // (0x9C00 instead of 0x9800)
+    fn map_pixel(&self, high_map: bool, x: u8, y: u8) -> BgPixel {
+        let (tile, attributes) = self.map_entry(high_map, x / 8, y);
+        let (low, high) = self.tile_row(tile, attributes, y);
+        let bit = if attributes & 0x20 != 0 { x % 8 } else { 7 - (x % 8) };
+        BgPixel {
+            color: ((high >> bit) & 1) << 1 | ((low >> bit) & 1),
+            palette: attributes & 0x07,
+            priority: attributes & 0x80 != 0,
+        }
+    }
+
+    // Tile number and, on CGB, the attributes stored at the same spot in
+    // VRAM bank 1
+    fn map_entry(&self, high_map: bool, column: u8, y: u8) -> (u8, u8) {
+        let tile_map = if high_map { 0x1C00 } else { 0x1800 };
+        let address = tile_map + (y as usize / 8) * 32 + (column & 31) as usize;
+        let attributes = if self.cgb { self.vram[0x2000 + address] } else { 0 };
+        (self.vram[address], attributes)
+    }
+
+    // The two data bytes of background tile `tile` for pixel row `y`, from
+    // the VRAM bank and with the Y flip given by its attributes
+    fn tile_row(&self, tile: u8, attributes: u8, y: u8) -> (u8, u8) {
+        // 0x8000 with unsigned tile numbers, or 0x9000 with signed ones
+        let tile_address = if self.lcd_control & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+        let bank = if attributes & 0x08 != 0 { 0x2000 } else { 0 };
+        let y = if attributes & 0x40 != 0 { 7 - y % 8 } else { y % 8 };
+        let row = bank + tile_address + y as usize * 2;
+        (self.vram[row], self.vram[row + 1])
+    }
+}
+
+impl Peripheral for PPU {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[
+            0x8000..=0x9FFF, 0xFE00..=0xFE9F, 0xFF40..=0xFF45, 0xFF47..=0xFF4B, 0xFF4F..=0xFF4F, 0xFF68..=0xFF6C,
+        ];
+        RANGES
+    }
+
+    fn read_byte(&self, addr: u16) -> u8 {
         match addr {
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize],
+            0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,
+            0x8000..=0x9FFF => self.vram[self.vram_bank * 0x2000 + (addr - 0x8000) as usize],
+            0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             0xFF40 => self.lcd_control,
-            0xFF41 => self.lcd_status,
+            0xFF41 => self.lcd_status | 0x80,
             0xFF42 => self.scroll_y,
             0xFF43 => self.scroll_x,
             0xFF44 => self.ly,
@@ -227,35 +827,481 @@ impl PPU {
             0xFF49 => self.obj_palette1,
             0xFF4A => self.window_y,
             0xFF4B => self.window_x,
-            0xFF46 => (self.dma_source >> 8) as u8,
+            0xFF4F | 0xFF68..=0xFF6C if !self.cgb => 0xFF,
+            0xFF4F => 0xFE | self.vram_bank as u8,
+            0xFF68 => self.bg_palette_index | 0x40,
+            0xFF69 if !self.vram_accessible() => 0xFF,
+            0xFF69 => self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize],
+            0xFF6A => self.obj_palette_index | 0x40,
+            0xFF6B if !self.vram_accessible() => 0xFF,
+            0xFF6B => self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize],
+            0xFF6C => 0xFE | self.obj_priority,
             _ => panic!("Invalid PPU register address: {:04X}", addr),
         }
     }
 
-    pub fn write_byte(&mut self, addr: u16, value: u8) {
+    fn write_byte(&mut self, addr: u16, value: u8) {
         match addr {
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize] = value,
+            0x8000..=0x9FFF if !self.vram_accessible() => (),
+            0x8000..=0x9FFF => self.vram[self.vram_bank * 0x2000 + (addr - 0x8000) as usize] = value,
+            0xFE00..=0xFE9F if !self.oam_accessible() => (),
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = value,
-            0xFF40 => self.lcd_control = value,
-            0xFF41 => self.lcd_status = value,
+            0xFF40 => self.set_lcd_control(value),
+            0xFF41 => {
+                // Mode and coincidence bits are read-only
+                self.lcd_status = (self.lcd_status & 0x07) | (value & 0x78);
+                self.update_stat();
+            }
             0xFF42 => self.scroll_y = value,
             0xFF43 => self.scroll_x = value,
             0xFF44 => (), // LY is read-only
-            0xFF45 => self.ly_compare = value,
+            0xFF45 => {
+                self.ly_compare = value;
+                self.update_stat();
+            }
             0xFF47 => self.bg_palette = value,
             0xFF48 => self.obj_palette0 = value,
             0xFF49 => self.obj_palette1 = value,
             0xFF4A => self.window_y = value,
             0xFF4B => self.window_x = value,
-            0xFF46 => self.start_dma(value),
+            0xFF4F | 0xFF68..=0xFF6C if !self.cgb => (),
+            0xFF4F => self.vram_bank = (value & 0x01) as usize,
+            0xFF68 => self.bg_palette_index = value & 0xBF,
+            0xFF69 => {
+                // Palette RAM is locked during mode 3, but the index still moves
+                if self.vram_accessible() {
+                    self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize] = value;
+                }
+                self.bg_palette_index = Self::increment_palette_index(self.bg_palette_index);
+            }
+            0xFF6A => self.obj_palette_index = value & 0xBF,
+            0xFF6B => {
+                if self.vram_accessible() {
+                    self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize] = value;
+                }
+                self.obj_palette_index = Self::increment_palette_index(self.obj_palette_index);
+            }
+            0xFF6C => self.obj_priority = value & 0x01,
             _ => panic!("Invalid PPU register address: {:04X}", addr),
         }
     }
 
-    fn start_dma(&mut self, value: u8) {
-        self.dma_source = (value as u16) << 8;
-        self.dma_active = true;
-        self.dma_byte = 0;
-        self.dma_start_delay = 2;
+    fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.is_lcd_enabled() {
+            return;
+        }
+
+        self.mode_clock += cycles;
+
+        // A long tick, like the CPU sitting out a general-purpose HDMA, can
+        // span several modes and lines. Keep going until the current mode
+        // needs more time than is left.
+        loop {
+            let clock = self.mode_clock;
+            match self.current_mode {
+                2 => self.handle_oam_scan(),
+                3 => self.handle_pixel_transfer(),
+                0 => self.handle_hblank(),
+                1 => self.handle_vblank(),
+                _ => unreachable!(),
+            }
+            if self.mode_clock == clock {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A FIFO-rendered PPU showing the window from line 0. Window tiles
+    // have only their leftmost pixel set, in color 1; the background is
+    // color 0.
+    fn window_ppu(scroll_x: u8, window_x: u8) -> PPU {
+        let mut ppu = PPU::new();
+        ppu.set_renderer(Renderer::Fifo);
+        ppu.write_byte(0x8010, 0x80);
+        for addr in 0x9C00..=0x9FFF {
+            ppu.write_byte(addr, 0x01);
+        }
+        ppu.write_byte(0xFF43, scroll_x);
+        ppu.write_byte(0xFF47, 0xE4);
+        ppu.write_byte(0xFF4A, 0);
+        ppu.write_byte(0xFF4B, window_x);
+        // LCD on, window map at 0x9C00, window on, tiles at 0x8000, BG on
+        ppu.write_byte(0xFF40, 0xF1);
+        ppu
+    }
+
+    // Run until line 0 of the first frame that is shown has been drawn
+    fn draw_first_line(ppu: &mut PPU) {
+        for _ in 0..(70224 + 456) / 4 {
+            ppu.tick(4);
+        }
+    }
+
+    // Shades of that line
+    fn first_line(mut ppu: PPU) -> Vec<u8> {
+        draw_first_line(&mut ppu);
+        ppu.framebuffer[..160].to_vec()
     }
-}
\ No newline at end of file
+
+    fn columns_set(line: &[u8]) -> Vec<usize> {
+        line.iter().enumerate().filter(|&(_, &shade)| shade != 0).map(|(x, _)| x).collect()
+    }
+
+    // A DMG PPU with identity palettes. Tiles 1-3 are solid in colors 1-3,
+    // and tile 4 has only its leftmost column set, in color 1.
+    fn dmg_ppu(renderer: Renderer) -> PPU {
+        let mut ppu = PPU::new();
+        ppu.set_renderer(renderer);
+        for row in 0..8 {
+            ppu.write_byte(0x8010 + row * 2, 0xFF);
+            ppu.write_byte(0x8021 + row * 2, 0xFF);
+            ppu.write_byte(0x8030 + row * 2, 0xFF);
+            ppu.write_byte(0x8031 + row * 2, 0xFF);
+            ppu.write_byte(0x8040 + row * 2, 0x80);
+        }
+        ppu.write_byte(0xFF47, 0xE4);
+        ppu.write_byte(0xFF48, 0xE4);
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut PPU, index: u16, y: u8, x: u8, tile: u8) {
+        for (offset, value) in [y, x, tile, 0].into_iter().enumerate() {
+            ppu.write_byte(0xFE00 + index * 4 + offset as u16, value);
+        }
+    }
+
+    #[test]
+    fn signed_tile_numbers_read_from_0x8800() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = dmg_ppu(renderer);
+            // Tile 0x80 is at 0x8800 and tile 0x00 at 0x9000
+            for row in 0..8 {
+                ppu.write_byte(0x8800 + row * 2, 0xFF);
+                ppu.write_byte(0x9001 + row * 2, 0xFF);
+            }
+            ppu.write_byte(0x9800, 0x80);
+            // LCD on, tiles at 0x8800, BG on
+            ppu.write_byte(0xFF40, 0x81);
+            let line = first_line(ppu);
+            assert_eq!(line[..8], [1; 8], "{:?}", renderer);
+            assert_eq!(line[8..16], [2; 8], "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn scx_scrolls_by_single_pixels() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            for scroll_x in [0, 3, 7, 8, 13] {
+                let mut ppu = dmg_ppu(renderer);
+                for addr in 0x9800..0x9820 {
+                    ppu.write_byte(addr, 4);
+                }
+                ppu.write_byte(0xFF43, scroll_x);
+                ppu.write_byte(0xFF40, 0x91);
+                let first = (8 - scroll_x as usize % 8) % 8;
+                let expected: Vec<usize> = (first..160).step_by(8).collect();
+                assert_eq!(columns_set(&first_line(ppu)), expected, "{:?}, SCX = {}", renderer, scroll_x);
+            }
+        }
+    }
+
+    #[test]
+    fn only_ten_sprites_per_line() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = dmg_ppu(renderer);
+            for i in 0..11 {
+                set_sprite(&mut ppu, i, 16, 8 + i as u8 * 12, 3);
+            }
+            // LCD on, OBJs on, tiles at 0x8000
+            ppu.write_byte(0xFF40, 0x92);
+            let line = first_line(ppu);
+            for i in 0..11 {
+                let shade = if i < 10 { 3 } else { 0 };
+                assert_eq!(line[i * 12..i * 12 + 8], [shade; 8], "{:?}, sprite {}", renderer, i);
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_with_the_smaller_x_is_on_top() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = dmg_ppu(renderer);
+            set_sprite(&mut ppu, 0, 16, 12, 1);
+            set_sprite(&mut ppu, 1, 16, 8, 2);
+            // With equal X the lower OAM index wins
+            set_sprite(&mut ppu, 2, 16, 40, 1);
+            set_sprite(&mut ppu, 3, 16, 40, 2);
+            ppu.write_byte(0xFF40, 0x92);
+            let line = first_line(ppu);
+            assert_eq!(line[..12], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1], "{:?}", renderer);
+            assert_eq!(line[32..40], [1; 8], "{:?}", renderer);
+        }
+    }
+
+    // Tick until `done` holds, returning the interrupts raised on the way
+    fn tick_until(ppu: &mut PPU, done: impl Fn(&PPU) -> bool) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..70224 {
+            if done(ppu) {
+                return interrupts;
+            }
+            ppu.tick(4);
+            interrupts |= ppu.take_interrupts();
+        }
+        panic!("PPU never reached the state");
+    }
+
+    fn at(ly: u8, mode: u8) -> impl Fn(&PPU) -> bool {
+        move |ppu| ppu.ly == ly && ppu.current_mode == mode
+    }
+
+    #[test]
+    fn ly_matching_lyc_sets_the_flag_and_interrupts() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF45, 2);
+        ppu.write_byte(0xFF41, 0x40);
+        ppu.write_byte(0xFF40, 0x80);
+        assert_eq!(tick_until(&mut ppu, at(1, 0)), 0);
+        assert_eq!(ppu.read_byte(0xFF41) & 0x04, 0);
+        assert_eq!(tick_until(&mut ppu, at(2, 2)), LCD_STAT_INTERRUPT);
+        assert_eq!(ppu.read_byte(0xFF41) & 0x04, 0x04);
+        // Still set for the rest of the line without interrupting again
+        assert_eq!(tick_until(&mut ppu, at(2, 0)), 0);
+        assert_eq!(ppu.read_byte(0xFF41) & 0x04, 0x04);
+        tick_until(&mut ppu, at(3, 2));
+        assert_eq!(ppu.read_byte(0xFF41) & 0x04, 0);
+    }
+
+    #[test]
+    fn stat_line_staying_high_blocks_new_interrupts() {
+        // With only the mode 2 source, every line interrupts
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF41, 0x20);
+        ppu.write_byte(0xFF40, 0x80);
+        ppu.take_interrupts();
+        assert_eq!(tick_until(&mut ppu, at(1, 2)), LCD_STAT_INTERRUPT);
+
+        // With mode 0 too, the line is already high from HBlank when mode 2
+        // starts, so only HBlank interrupts
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF41, 0x28);
+        ppu.write_byte(0xFF40, 0x80);
+        ppu.take_interrupts();
+        assert_eq!(tick_until(&mut ppu, at(0, 0)), LCD_STAT_INTERRUPT);
+        assert_eq!(tick_until(&mut ppu, at(1, 2)), 0);
+        assert_eq!(tick_until(&mut ppu, at(1, 3)), 0);
+        assert_eq!(tick_until(&mut ppu, at(1, 0)), LCD_STAT_INTERRUPT);
+    }
+
+    #[test]
+    fn vblank_interrupts_at_line_144() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF40, 0x80);
+        assert_eq!(tick_until(&mut ppu, at(143, 0)), 0);
+        assert_eq!(tick_until(&mut ppu, at(144, 1)), VBLANK_INTERRUPT);
+        assert_eq!(tick_until(&mut ppu, at(0, 2)), 0);
+    }
+
+    #[test]
+    fn vblank_is_a_stat_source() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF41, 0x10);
+        ppu.write_byte(0xFF40, 0x80);
+        assert_eq!(tick_until(&mut ppu, at(143, 0)), 0);
+        assert_eq!(tick_until(&mut ppu, at(144, 1)), VBLANK_INTERRUPT | LCD_STAT_INTERRUPT);
+        assert_eq!(ppu.read_byte(0xFF41) & 0x03, 1);
+        assert_eq!(tick_until(&mut ppu, at(153, 1)), 0);
+    }
+
+    #[test]
+    fn vram_is_locked_in_mode_3() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0x8000, 0x12);
+        ppu.write_byte(0xFF40, 0x80);
+        for (mode, accessible) in [(2, true), (3, false), (0, true)] {
+            tick_until(&mut ppu, at(0, mode));
+            let value = if accessible { 0x12 } else { 0xFF };
+            assert_eq!(ppu.read_byte(0x8000), value, "mode {}", mode);
+        }
+
+        tick_until(&mut ppu, at(1, 3));
+        ppu.write_byte(0x8000, 0x34);
+        tick_until(&mut ppu, at(1, 0));
+        assert_eq!(ppu.read_byte(0x8000), 0x12);
+        ppu.write_byte(0x8000, 0x34);
+        assert_eq!(ppu.read_byte(0x8000), 0x34);
+    }
+
+    #[test]
+    fn oam_is_locked_in_modes_2_and_3() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFE00, 0x12);
+        ppu.write_byte(0xFF40, 0x80);
+        for (mode, accessible) in [(2, false), (3, false), (0, true)] {
+            tick_until(&mut ppu, at(0, mode));
+            let value = if accessible { 0x12 } else { 0xFF };
+            assert_eq!(ppu.read_byte(0xFE00), value, "mode {}", mode);
+        }
+
+        for mode in [2, 3] {
+            tick_until(&mut ppu, at(1, mode));
+            ppu.write_byte(0xFE00, 0x34);
+        }
+        tick_until(&mut ppu, at(1, 0));
+        assert_eq!(ppu.read_byte(0xFE00), 0x12);
+        tick_until(&mut ppu, at(144, 1));
+        ppu.write_byte(0xFE00, 0x34);
+        assert_eq!(ppu.read_byte(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn turning_the_lcd_off_resets_ly_and_the_mode() {
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF40, 0x80);
+        tick_until(&mut ppu, at(50, 3));
+        ppu.write_byte(0xFF40, 0x00);
+        assert_eq!(ppu.read_byte(0xFF44), 0);
+        assert_eq!(ppu.read_byte(0xFF41) & 0x03, 0);
+
+        // Both memories are open while it's off, and nothing advances
+        ppu.write_byte(0x8000, 0x12);
+        ppu.write_byte(0xFE00, 0x34);
+        ppu.tick(70224);
+        assert_eq!((ppu.read_byte(0x8000), ppu.read_byte(0xFE00)), (0x12, 0x34));
+        assert_eq!(ppu.read_byte(0xFF44), 0);
+    }
+
+    #[test]
+    fn palette_indices_auto_increment_and_wrap() {
+        for (index, data) in [(0xFF68, 0xFF69), (0xFF6A, 0xFF6B)] {
+            let mut ppu = PPU::new();
+            ppu.set_cgb_mode(true);
+            ppu.write_byte(index, 0xBE);
+            for value in [0x11, 0x22, 0x33] {
+                ppu.write_byte(data, value);
+            }
+            assert_eq!(ppu.read_byte(index), 0xC1, "{:04X}", index);
+
+            // Reads and writes without bit 7 leave the index alone
+            ppu.write_byte(index, 0x3E);
+            assert_eq!(ppu.read_byte(data), 0x11);
+            assert_eq!(ppu.read_byte(data), 0x11);
+            ppu.write_byte(index, 0x3F);
+            assert_eq!(ppu.read_byte(data), 0x22);
+            ppu.write_byte(index, 0x00);
+            assert_eq!(ppu.read_byte(data), 0x33);
+            ppu.write_byte(data, 0x44);
+            assert_eq!(ppu.read_byte(data), 0x44);
+            assert_eq!(ppu.read_byte(index), 0x40);
+        }
+    }
+
+    #[test]
+    fn vbk_switches_vram_banks() {
+        let mut ppu = PPU::new();
+        ppu.set_cgb_mode(true);
+        assert_eq!(ppu.read_byte(0xFF4F), 0xFE);
+        ppu.write_byte(0x9800, 0x05);
+        ppu.write_byte(0xFF4F, 0x01);
+        assert_eq!(ppu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(ppu.read_byte(0x9800), 0x00);
+        ppu.write_byte(0x9800, 0x20);
+        ppu.write_byte(0xFF4F, 0x00);
+        assert_eq!(ppu.read_byte(0x9800), 0x05);
+
+        // Only the CGB has a second bank
+        let mut ppu = PPU::new();
+        ppu.write_byte(0xFF4F, 0x01);
+        assert_eq!(ppu.read_byte(0xFF4F), 0xFF);
+        ppu.write_byte(0x9800, 0x05);
+        assert_eq!(ppu.vram[0x1800], 0x05);
+    }
+
+    #[test]
+    fn bank_1_holds_the_bg_attributes() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = dmg_ppu(renderer);
+            ppu.set_cgb_mode(true);
+            for addr in 0x9800..0x9820 {
+                ppu.write_byte(addr, 4);
+            }
+            // The first tile is X flipped and uses palette 2, where color 1
+            // is red
+            ppu.write_byte(0xFF4F, 0x01);
+            ppu.write_byte(0x9800, 0x22);
+            ppu.write_byte(0xFF4F, 0x00);
+            ppu.write_byte(0xFF68, 0x80 | (2 * 8 + 2));
+            ppu.write_byte(0xFF69, 0x1F);
+            ppu.write_byte(0xFF69, 0x00);
+            ppu.write_byte(0xFF40, 0x91);
+
+            draw_first_line(&mut ppu);
+            assert_eq!(columns_set(&ppu.framebuffer[..160])[..2], [7, 8], "{:?}", renderer);
+            assert_eq!(ppu.color_framebuffer[7], 0x001F, "{:?}", renderer);
+            assert_eq!(ppu.color_framebuffer[8], WHITE, "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn bg_priority_attribute_covers_sprites() {
+        let mut ppu = PPU::new();
+        ppu.set_cgb_mode(true);
+        ppu.lcd_control = 0x03;
+        let obj = ObjPixel { color: 2, palette: 0, behind_bg: false, oam_index: 0 };
+        let bg = |color, priority| BgPixel { color, palette: 0, priority };
+
+        ppu.put_pixel(0, bg(1, true), Some(obj));
+        ppu.put_pixel(1, bg(0, true), Some(obj));
+        ppu.put_pixel(2, bg(1, false), Some(obj));
+        ppu.put_pixel(3, bg(1, false), Some(ObjPixel { behind_bg: true, ..obj }));
+        assert_eq!(ppu.framebuffer[..4], [1, 2, 2, 1]);
+
+        // With LCDC bit 0 clear OBJs always win
+        ppu.lcd_control = 0x02;
+        ppu.put_pixel(0, bg(1, true), Some(obj));
+        ppu.put_pixel(3, bg(1, false), Some(ObjPixel { behind_bg: true, ..obj }));
+        assert_eq!(ppu.framebuffer[..4], [2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn long_ticks_keep_the_timing_of_short_ones() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            for cycles in [456, 4096, 70224, 3 * 70224 + 1000] {
+                let mut short = window_ppu(0, 7);
+                short.set_renderer(renderer);
+                for _ in 0..cycles / 4 {
+                    short.tick(4);
+                }
+                let mut long = window_ppu(0, 7);
+                long.set_renderer(renderer);
+                long.tick(cycles);
+
+                let state = |ppu: &PPU| (ppu.ly, ppu.current_mode, ppu.mode_clock, ppu.interrupts);
+                assert_eq!(state(&long), state(&short), "{:?}, {} cycles", renderer, cycles);
+            }
+        }
+    }
+
+    #[test]
+    fn window_at_wx_7_ignores_fine_scroll() {
+        for scroll_x in [0, 3, 7] {
+            let line = first_line(window_ppu(scroll_x, 7));
+            assert_eq!(columns_set(&line), (0..160).step_by(8).collect::<Vec<_>>(), "SCX = {}", scroll_x);
+        }
+    }
+
+    #[test]
+    fn window_left_of_wx_7_is_cut_off() {
+        let line = first_line(window_ppu(5, 3));
+        assert_eq!(columns_set(&line), (4..160).step_by(8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,263 @@
+// Recording gameplay to disk, one emulated frame at a time. Frames are
+// timestamped by their number, so the files play back at exactly
+// CLOCK_HZ / CYCLES_PER_FRAME (59.73 Hz) however fast the emulator ran.
+
+use crate::gameboy::{CLOCK_HZ, CYCLES_PER_FRAME};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    // Animated PNG, for short clips
+    Apng,
+    // Uncompressed YCbCr 4:4:4 streamed to disk, for long captures to
+    // encode offline
+    Y4m,
+}
+
+impl VideoFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "apng" | "png" => Some(VideoFormat::Apng),
+            "y4m" => Some(VideoFormat::Y4m),
+            _ => None,
+        }
+    }
+
+    // Guess from a file name's extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        Self::parse(&path.extension()?.to_str()?.to_ascii_lowercase())
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::Apng => "png",
+            VideoFormat::Y4m => "y4m",
+        }
+    }
+}
+
+pub struct Recorder {
+    width: usize,
+    height: usize,
+    frames: u64,
+    output: Output,
+}
+
+enum Output {
+    Apng(Apng),
+    Y4m(Y4m),
+}
+
+impl Recorder {
+    // Every frame passed to write_frame must be width x height
+    pub fn create(path: &Path, format: VideoFormat, width: usize, height: usize) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let output = match format {
+            VideoFormat::Apng => Output::Apng(Apng::new(path, file, width, height)?),
+            VideoFormat::Y4m => Output::Y4m(Y4m::new(file, width, height)?),
+        };
+        Ok(Recorder { width, height, frames: 0, output })
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Add a frame of 0x00RRGGBB pixels
+    pub fn write_frame(&mut self, pixels: &[u32]) -> io::Result<()> {
+        assert_eq!(pixels.len(), self.width * self.height, "frame has the wrong size");
+        match &mut self.output {
+            Output::Apng(apng) => apng.push(pixels)?,
+            Output::Y4m(y4m) => y4m.write_frame(pixels)?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Apng(apng) => apng.finish(),
+            Output::Y4m(mut y4m) => y4m.file.flush(),
+        }
+    }
+}
+
+// APNG frame delays are 16-bit fractions of a second. In units of 1/10000s
+// a run of identical frames can last up to 390 frames (6.5 seconds).
+const APNG_TIME_UNITS: u64 = 10000;
+const APNG_MAX_RUN: u32 = 390;
+
+// The frame count in acTL isn't known until the end, so it starts out as a
+// placeholder and finish() rewrites the chunk. It comes right after the PNG
+// signature and the IHDR chunk.
+const APNG_ACTL_OFFSET: u64 = 8 + 25;
+
+struct Apng {
+    path: PathBuf,
+    writer: png::Writer<BufWriter<File>>,
+    pending: Option<(Vec<u8>, u32)>,  // RGB pixels and how many frames they're shown
+    start: u64,                       // frames before the pending one
+    written: u32,                     // APNG frames in the file
+}
+
+impl Apng {
+    fn new(path: &Path, file: BufWriter<File>, width: usize, height: usize) -> io::Result<Self> {
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(u32::MAX, 0).map_err(io::Error::other)?;
+        let writer = encoder.write_header().map_err(io::Error::other)?;
+        Ok(Apng { path: path.to_path_buf(), writer, pending: None, start: 0, written: 0 })
+    }
+
+    fn push(&mut self, pixels: &[u32]) -> io::Result<()> {
+        let rgb: Vec<u8> = pixels.iter().flat_map(|&pixel| {
+            let [_, r, g, b] = pixel.to_be_bytes();
+            [r, g, b]
+        }).collect();
+        // Still screens are common, so repeats just lengthen the frame
+        // waiting to be written
+        match &mut self.pending {
+            Some((last, count)) if *last == rgb && *count < APNG_MAX_RUN => *count += 1,
+            _ => {
+                self.write_pending()?;
+                self.pending = Some((rgb, 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let Some((rgb, count)) = self.pending.take() else {
+            return Ok(());
+        };
+        let end = self.start + count as u64;
+        let delay = Self::time(end) - Self::time(self.start);
+        self.writer.set_frame_delay(delay as u16, APNG_TIME_UNITS as u16).map_err(io::Error::other)?;
+        self.writer.write_image_data(&rgb).map_err(io::Error::other)?;
+        self.start = end;
+        self.written += 1;
+        Ok(())
+    }
+
+    // Frame `frame` starts at this many APNG_TIME_UNITS. Each delay is the
+    // difference of two of these, so rounding never adds up to drift.
+    fn time(frame: u64) -> u64 {
+        (frame * CYCLES_PER_FRAME as u64 * APNG_TIME_UNITS + CLOCK_HZ as u64 / 2) / CLOCK_HZ as u64
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.writer.finish().map_err(io::Error::other)?;
+        // An APNG needs at least one frame, so an empty recording leaves no
+        // file behind
+        if self.written == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(APNG_ACTL_OFFSET))?;
+        let actl = png::AnimationControl { num_frames: self.written, num_plays: 0 };
+        actl.encode(&mut file).map_err(io::Error::other)
+    }
+}
+
+struct Y4m {
+    file: BufWriter<File>,
+    planes: Vec<u8>,
+}
+
+impl Y4m {
+    fn new(mut file: BufWriter<File>, width: usize, height: usize) -> io::Result<Self> {
+        // The frame rate is given as the exact fraction 4194304:70224
+        writeln!(
+            file,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444 XCOLORRANGE=FULL",
+            width, height, CLOCK_HZ, CYCLES_PER_FRAME,
+        )?;
+        Ok(Y4m { file, planes: vec![0; width * height * 3] })
+    }
+
+    // Full-range BT.601, with no chroma subsampling so small sprites keep
+    // their colors
+    fn write_frame(&mut self, pixels: &[u32]) -> io::Result<()> {
+        let (y, chroma) = self.planes.split_at_mut(pixels.len());
+        let (cb, cr) = chroma.split_at_mut(pixels.len());
+        for (i, &pixel) in pixels.iter().enumerate() {
+            let [_, r, g, b] = pixel.to_be_bytes().map(|channel| channel as i32);
+            y[i] = ((299 * r + 587 * g + 114 * b + 500) / 1000) as u8;
+            cb[i] = (128 + (-169 * r - 331 * g + 500 * b).div_euclid(1000)).clamp(0, 255) as u8;
+            cr[i] = (128 + (500 * r - 419 * g - 81 * b).div_euclid(1000)).clamp(0, 255) as u8;
+        }
+        self.file.write_all(b"FRAME\n")?;
+        self.file.write_all(&self.planes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unique per test and process, so tests running in parallel never share
+    // a file
+    fn temp_path(name: &str, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusty_boy_{}_{}.{}", name, std::process::id(), extension))
+    }
+
+    #[test]
+    fn apng_streams_frames_and_counts_them_at_the_end() {
+        let path = temp_path("apng_streams", "png");
+        let mut recorder = Recorder::create(&path, VideoFormat::Apng, 2, 1).unwrap();
+        for pixels in [[0, 0], [0, 0], [0xFFFFFF, 0], [0, 0]] {
+            recorder.write_frame(&pixels).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let actl = reader.info().animation_control.unwrap();
+        assert_eq!((actl.num_frames, actl.num_plays), (3, 0));
+        let mut delays = Vec::new();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        while reader.next_frame(&mut buffer).is_ok() {
+            let fctl = reader.info().frame_control.unwrap();
+            delays.push((fctl.delay_num, fctl.delay_den));
+        }
+        fs::remove_file(&path).unwrap();
+        // The repeated first frame is shown for two frame times
+        assert_eq!(delays, [(335, 10000), (167, 10000), (168, 10000)]);
+    }
+
+    #[test]
+    fn empty_apng_recording_leaves_no_file() {
+        let path = temp_path("empty_apng", "png");
+        Recorder::create(&path, VideoFormat::Apng, 2, 1).unwrap().finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn y4m_writes_a_header_then_full_planes_per_frame() {
+        let path = temp_path("y4m_planes", "y4m");
+        let mut recorder = Recorder::create(&path, VideoFormat::Y4m, 2, 1).unwrap();
+        recorder.write_frame(&[0x000000, 0xFFFFFF]).unwrap();
+        recorder.write_frame(&[0xFFFFFF, 0x000000]).unwrap();
+        recorder.finish().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = b"YUV4MPEG2 W2 H1 F4194304:70224 Ip A1:1 C444 XCOLORRANGE=FULL\n";
+        let mut expected = header.to_vec();
+        // Y, Cb and Cr planes of 2 x 1 pixels each: black is 0/128/128 and
+        // white 255/128/128
+        expected.extend(b"FRAME\n");
+        expected.extend([0, 255, 128, 128, 128, 128]);
+        expected.extend(b"FRAME\n");
+        expected.extend([255, 0, 128, 128, 128, 128]);
+        assert_eq!(bytes, expected);
+    }
+}
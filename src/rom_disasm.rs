@@ -0,0 +1,356 @@
+use crate::disasm::{self, Flow, Instruction, Operand, RomView, SymbolTable};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+const BANK_SIZE: usize = 0x4000;
+
+// Addresses the hardware can start executing from: the cartridge entry
+// point, the RST vectors and the interrupt vectors
+const ENTRY_POINTS: [(u16, &str); 14] = [
+    (0x0100, "Entry"),
+    (0x0000, "RST_00"),
+    (0x0008, "RST_08"),
+    (0x0010, "RST_10"),
+    (0x0018, "RST_18"),
+    (0x0020, "RST_20"),
+    (0x0028, "RST_28"),
+    (0x0030, "RST_30"),
+    (0x0038, "RST_38"),
+    (0x0040, "VBlankInterrupt"),
+    (0x0048, "LCDCInterrupt"),
+    (0x0050, "TimerInterrupt"),
+    (0x0058, "SerialInterrupt"),
+    (0x0060, "JoypadInterrupt"),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum ByteKind {
+    Data,
+    Code,     // first byte of an instruction
+    Operand,  // remaining bytes of an instruction
+}
+
+// Static disassembly of a whole ROM image. Code is found by following
+// control flow from the entry points, everything else is emitted as data.
+pub struct RomDisassembly<'a> {
+    rom: &'a [u8],
+    kinds: Vec<ByteKind>,
+    // ROM bank mapped at 0x4000 while executing each instruction in bank 0
+    mapped_banks: HashMap<usize, u16>,
+    labels: BTreeMap<usize, String>,
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> RomDisassembly<'a> {
+    pub fn analyze(rom: &'a [u8], symbols: Option<&'a SymbolTable>) -> Self {
+        let mut disassembly = RomDisassembly {
+            rom,
+            kinds: vec![ByteKind::Data; rom.len()],
+            mapped_banks: HashMap::new(),
+            labels: BTreeMap::new(),
+            symbols,
+        };
+
+        // Every MBC maps bank 1 at power on
+        let mut pending: Vec<(u16, u16)> = Vec::new();
+        for &(addr, name) in ENTRY_POINTS.iter().rev() {
+            if (addr as usize) < rom.len() {
+                disassembly.labels.insert(addr as usize, name.to_string());
+                pending.push((addr, 1));
+            }
+        }
+
+        let mut targets: Vec<(usize, &str)> = Vec::new();
+        while let Some((addr, bank)) = pending.pop() {
+            disassembly.trace(addr, bank, &mut pending, &mut targets);
+        }
+
+        // Labels can only go where an instruction or data byte starts
+        for (offset, kind) in targets {
+            if disassembly.kinds[offset] != ByteKind::Operand {
+                let name = format!("{}_{:03X}_{:04X}", kind, offset / BANK_SIZE, Self::address(offset));
+                disassembly.labels.entry(offset).or_insert(name);
+            }
+        }
+
+        // User supplied names win over generated ones
+        if let Some(symbols) = symbols {
+            for (bank, addr, name) in symbols.iter() {
+                if addr >= 0x8000 || (addr >= 0x4000 && bank == 0) {
+                    continue;
+                }
+                if let Some(offset) = disassembly.offset(addr, bank) {
+                    if disassembly.kinds[offset] != ByteKind::Operand {
+                        disassembly.labels.insert(offset, name.to_string());
+                    }
+                }
+            }
+        }
+        disassembly
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.rom.len().div_ceil(BANK_SIZE)
+    }
+
+    // CPU address an offset in the ROM file is visible at
+    fn address(offset: usize) -> u16 {
+        if offset < BANK_SIZE {
+            offset as u16
+        } else {
+            (0x4000 + offset % BANK_SIZE) as u16
+        }
+    }
+
+    // ROM offset reached by jumping to `addr` with `bank` mapped at 0x4000
+    fn offset(&self, addr: u16, bank: u16) -> Option<usize> {
+        RomView::new(self.rom, bank).offset(addr)
+    }
+
+    // Follow code linearly from `addr`, queueing every branch target
+    fn trace(&mut self, addr: u16, bank: u16, pending: &mut Vec<(u16, u16)>, targets: &mut Vec<(usize, &'static str)>) {
+        let mut pc = addr;
+        let mut bank = bank;
+        let mut accumulator: Option<u8> = None;
+
+        while let Some(offset) = self.offset(pc, bank) {
+            if self.kinds[offset] != ByteKind::Data {
+                break;
+            }
+
+            let instruction = disasm::decode(&RomView::new(self.rom, bank), pc);
+            let length = instruction.length as usize;
+            let bank_end = (offset / BANK_SIZE + 1) * BANK_SIZE;
+            if instruction.flow == Flow::Invalid
+                || offset + length > bank_end.min(self.rom.len())
+                || self.kinds[offset + 1..offset + length].iter().any(|&kind| kind != ByteKind::Data)
+                || (instruction.bytes[0] == 0x10 && instruction.bytes[1] != 0x00)
+            {
+                break;
+            }
+
+            self.kinds[offset] = ByteKind::Code;
+            for kind in &mut self.kinds[offset + 1..offset + length] {
+                *kind = ByteKind::Operand;
+            }
+            if offset < BANK_SIZE {
+                self.mapped_banks.insert(offset, bank);
+            }
+
+            // Track "ld a, n / ld [$2000], a" so calls into 0x4000-0x7FFF
+            // land in the right bank
+            match (instruction.mnemonic, instruction.operands.as_slice()) {
+                ("ld", [Operand::Register("a"), Operand::Imm8(value)]) => accumulator = Some(*value),
+                ("xor", [Operand::Register("a"), Operand::Register("a")]) => accumulator = Some(0),
+                ("ld", [Operand::Address(0x2000..=0x3FFF), Operand::Register("a")]) if pc < 0x4000 => {
+                    if let Some(value) = accumulator {
+                        bank = (value as u16).max(1);
+                    }
+                }
+                (_, [Operand::Register("a"), ..]) => accumulator = None,
+                _ => (),
+            }
+
+            let mut follow = |target: u16, kind: &'static str| {
+                if let Some(target_offset) = self.offset(target, bank) {
+                    targets.push((target_offset, kind));
+                    pending.push((target, bank));
+                }
+            };
+            match instruction.flow {
+                Flow::Continue => (),
+                Flow::Jump(target) => {
+                    follow(target, "Jump");
+                    break;
+                }
+                Flow::Branch(target) => follow(target, "Jump"),
+                Flow::Call(target) => {
+                    follow(target, "Call");
+                    // A lone 0xFF is far more likely to be padding than a
+                    // crash handler that returns, so don't run into it
+                    if instruction.bytes[0] == 0xFF {
+                        break;
+                    }
+                }
+                Flow::Return | Flow::Indirect | Flow::Invalid => break,
+            }
+            pc = instruction.next_address();
+        }
+    }
+
+    // User symbols that point outside the ROM and need an EQU to assemble
+    fn constants(&self) -> Vec<(u16, &str)> {
+        let Some(symbols) = self.symbols else {
+            return Vec::new();
+        };
+        let mut constants: Vec<(u16, &str)> = symbols.iter()
+            .filter(|&(_, addr, _)| addr >= 0x8000)
+            .map(|(_, addr, name)| (addr, name))
+            .collect();
+        constants.sort();
+        constants
+    }
+
+    // Emit RGBDS source that assembles back to the original ROM
+    pub fn to_source(&self, name: &str) -> String {
+        let constants = self.constants();
+        let mut table = SymbolTable::new();
+        for (&offset, label) in &self.labels {
+            table.insert((offset / BANK_SIZE) as u16, Self::address(offset), label);
+        }
+        for &(addr, symbol) in &constants {
+            table.insert(0, addr, symbol);
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(out, "; Disassembly of {}", name);
+        let _ = writeln!(out, "; rgbasm -o game.o game.asm && rgblink -o game.gb game.o");
+        if !constants.is_empty() {
+            out.push('\n');
+            for (addr, symbol) in constants {
+                let _ = writeln!(out, "DEF {} EQU ${:04X}", symbol, addr);
+            }
+        }
+
+        for bank in 0..self.bank_count() {
+            out.push('\n');
+            if bank == 0 {
+                let _ = writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]");
+            } else {
+                let _ = writeln!(out, "SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank);
+            }
+
+            let end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+            let mut offset = bank * BANK_SIZE;
+            let mut data: Vec<u8> = Vec::new();
+            while offset < end {
+                let label = self.labels.get(&offset);
+                if !data.is_empty() && (label.is_some() || self.kinds[offset] == ByteKind::Code || data.len() == 16) {
+                    Self::write_data(&mut out, &data);
+                    data.clear();
+                }
+                if let Some(label) = label {
+                    out.push('\n');
+                    let _ = writeln!(out, "{}:", label);
+                }
+
+                if self.kinds[offset] == ByteKind::Code {
+                    let instruction = self.instruction_at(offset);
+                    let _ = writeln!(out, "    {}", instruction.format(Some(&table)));
+                    offset += instruction.length as usize;
+                } else {
+                    data.push(self.rom[offset]);
+                    offset += 1;
+                }
+            }
+            if !data.is_empty() {
+                Self::write_data(&mut out, &data);
+            }
+        }
+        out
+    }
+
+    fn instruction_at(&self, offset: usize) -> Instruction {
+        let bank = if offset < BANK_SIZE {
+            self.mapped_banks.get(&offset).copied().unwrap_or(0)
+        } else {
+            (offset / BANK_SIZE) as u16
+        };
+        disasm::decode(&RomView::new(self.rom, bank), Self::address(offset))
+    }
+
+    fn write_data(out: &mut String, data: &[u8]) {
+        let bytes: Vec<String> = data.iter().map(|byte| format!("${:02X}", byte)).collect();
+        let _ = writeln!(out, "    db {}", bytes.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 384-byte ROM: the entry point jumps to code that writes to I/O
+    // registers and calls a subroutine, with the header and an invalid
+    // opcode left as data
+    fn tiny_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x180];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x164].copy_from_slice(&[
+            0x3E, 0x12,             // ld a, $12
+            0xE0, 0x01,             // ldh [$FF01], a
+            0x0E, 0x02,             // ld c, $02
+            0xE2,                   // ldh [c], a
+            0xF0, 0x44,             // ldh a, [$FF44]
+            0xEA, 0x00, 0xC0,       // ld [$C000], a
+            0x3D,                   // dec a
+            0x20, 0xFD,             // jr nz, -3
+            0xCD, 0x70, 0x01,       // call $0170
+            0x18, 0xFE,             // jr -2
+        ]);
+        rom[0x170..0x173].copy_from_slice(&[0xCB, 0x37, 0xC9]);
+        rom[0x173] = 0xD3;
+        rom
+    }
+
+    #[test]
+    fn writes_rgbds_source() {
+        let rom = tiny_rom();
+        let source = RomDisassembly::analyze(&rom, None).to_source("tiny.gb");
+        let (header, rest) = source.split_at(source.find("\nRST_00:").unwrap());
+        assert_eq!(header, r#"; Disassembly of tiny.gb
+; rgbasm -o game.o game.asm && rgblink -o game.gb game.o
+
+SECTION "ROM Bank $000", ROM0[$0000]
+"#);
+        let entry = &rest[rest.find("\nEntry:").unwrap() + 1..];
+        assert_eq!(entry, r#"Entry:
+    nop
+    jp Jump_000_0150
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+
+Jump_000_0150:
+    ld a, $12
+    ldh [$FF01], a
+    ld c, $02
+    ldh [c], a
+    ldh a, [$FF44]
+    ld [$C000], a
+
+Jump_000_015C:
+    dec a
+    jr nz, Jump_000_015C
+    call Call_000_0170
+
+Jump_000_0162:
+    jr Jump_000_0162
+    db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+
+Call_000_0170:
+    swap a
+    ret
+    db $D3, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+"#);
+    }
+
+    #[test]
+    fn later_banks_go_in_romx_sections() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x00, 0x01]);
+        let source = RomDisassembly::analyze(&rom, None).to_source("banked.gb");
+        assert!(source.contains("\nSECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]\n"));
+    }
+
+    #[test]
+    fn symbols_name_code_and_constants() {
+        let rom = tiny_rom();
+        let symbols = SymbolTable::parse("00:0170 Swap\n00:C000 wCounter\n");
+        let source = RomDisassembly::analyze(&rom, Some(&symbols)).to_source("tiny.gb");
+        assert!(source.contains("DEF wCounter EQU $C000"));
+        assert!(source.contains("    ld [wCounter], a"));
+        assert!(source.contains("    call Swap"));
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// A captured frame, ready to be saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,   // RGB, row by row
+}
+
+impl Image {
+    // From 0x00RRGGBB pixels, as the PPU and video filters produce them
+    pub fn from_xrgb(width: usize, height: usize, pixels: &[u32]) -> Self {
+        assert_eq!(pixels.len(), width * height, "image has the wrong size");
+        let pixels = pixels.iter().map(|&rgb| {
+            let [_, r, g, b] = rgb.to_be_bytes();
+            [r, g, b]
+        });
+        Image { width, height, pixels: pixels.collect() }
+    }
+
+    // Encode as an RGB PNG. Each (keyword, text) pair goes into a tEXt chunk.
+    pub fn write_png<W: Write>(&self, writer: W, text: &[(&str, &str)]) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        for &(keyword, value) in text {
+            encoder.add_text_chunk(keyword.to_string(), value.to_string()).map_err(io::Error::other)?;
+        }
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(self.pixels.as_flattened()).map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+
+    pub fn save_png(&self, path: &Path, text: &[(&str, &str)]) -> io::Result<()> {
+        self.write_png(BufWriter::new(File::create(path)?), text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_round_trips_pixels_and_text() {
+        let image = Image::from_xrgb(3, 2, &[0xFF0000, 0x00FF00, 0x0000FF, 0x000000, 0x808080, 0xFFFFFF]);
+        let mut bytes = Vec::new();
+        image.write_png(&mut bytes, &[("Title", "TETRIS"), ("Frame", "1234")]).unwrap();
+
+        let mut reader = png::Decoder::new(bytes.as_slice()).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Rgb, png::BitDepth::Eight));
+        let text: Vec<_> = info.uncompressed_latin1_text.iter().map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str())).collect();
+        assert_eq!(text, [("Title", "TETRIS"), ("Frame", "1234")]);
+
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, image.pixels.as_flattened());
+        assert_eq!(image.pixels[4], [0x80, 0x80, 0x80]);
+    }
+}
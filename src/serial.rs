@@ -0,0 +1,98 @@
+use crate::interrupts::SERIAL_INTERRUPT;
+use crate::peripheral::Peripheral;
+use std::ops::RangeInclusive;
+
+// Serial port with nothing plugged into the link cable. Every byte sent
+// with the internal clock is kept so headless runs can read what a test
+// ROM printed.
+pub struct Serial {
+    sb: u8,             // Serial transfer data (0xFF01)
+    sc: u8,             // Serial transfer control (0xFF02)
+    transfer_clock: u32,
+    output: Vec<u8>,
+    interrupt: bool,
+}
+
+// 8 bits shifted out at 8192 Hz
+const TRANSFER_CYCLES: u32 = 8 * 512;
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            transfer_clock: 0,
+            output: Vec::new(),
+            interrupt: false,
+        }
+    }
+
+    fn transfer_active(&self) -> bool {
+        // Only the internal clock drives a transfer when nothing is connected
+        self.sc & 0x81 == 0x81
+    }
+
+    // Bytes sent so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Peripheral for Serial {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[0xFF01..=0xFF02];
+        RANGES
+    }
+
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => panic!("Invalid serial register address"),
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                self.sc = value;
+                if self.transfer_active() {
+                    self.output.push(self.sb);
+                    self.transfer_clock = 0;
+                }
+            }
+            _ => panic!("Invalid serial register address"),
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.transfer_active() {
+            return;
+        }
+
+        self.transfer_clock += cycles;
+        if self.transfer_clock >= TRANSFER_CYCLES {
+            // No link partner, so all ones are shifted in
+            self.sb = 0xFF;
+            self.sc &= 0x7F;
+            self.transfer_clock = 0;
+            self.interrupt = true;
+        }
+    }
+
+    // Once per completed transfer
+    fn take_interrupts(&mut self) -> u8 {
+        if std::mem::replace(&mut self.interrupt, false) { SERIAL_INTERRUPT } else { 0 }
+    }
+}
@@ -1,9 +1,20 @@
+use crate::interrupts::TIMER_INTERRUPT;
+use crate::peripheral::Peripheral;
+use std::ops::RangeInclusive;
+
 pub struct Timer {
     div: u16,  // 16-bit internal DIV counter
     tima: u8,  // Timer Counter
     tma: u8,   // Timer Modulo
     tac: u8,   // Timer Control
     last_bit: bool, // Last bit state for edge detection
+    interrupt: bool, // Set when TIMA overflows
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Timer {
@@ -14,17 +25,11 @@ impl Timer {
             tma: 0,
             tac: 0,
             last_bit: false,
+            interrupt: false,
         }
     }
 
-    pub fn update(&mut self, cycles: u32) {
-        println!("Updating timer. Cycles: {}", cycles);
-        for _ in 0..cycles {
-            self.tick();
-        }
-    }
-
-    fn tick(&mut self) {
+    fn clock(&mut self) {
         // Increment internal DIV counter
         self.div = self.div.wrapping_add(1);
 
@@ -56,13 +61,20 @@ impl Timer {
         if self.tima == 0xFF {
             // TIMA overflow
             self.tima = self.tma;
-            // TODO: Request timer interrupt
+            self.interrupt = true;
         } else {
             self.tima = self.tima.wrapping_add(1);
         }
     }
+}
+
+impl Peripheral for Timer {
+    fn ranges(&self) -> &[RangeInclusive<u16>] {
+        const RANGES: &[RangeInclusive<u16>] = &[0xFF04..=0xFF07];
+        RANGES
+    }
 
-    pub fn read_byte(&self, addr: u16) -> u8 {
+    fn read_byte(&self, addr: u16) -> u8 {
         match addr {
             0xFF04 => (self.div >> 8) as u8,
             0xFF05 => self.tima,
@@ -72,7 +84,7 @@ impl Timer {
         }
     }
 
-    pub fn write_byte(&mut self, addr: u16, value: u8) {
+    fn write_byte(&mut self, addr: u16, value: u8) {
         match addr {
             0xFF04 => self.div = 0,
             0xFF05 => self.tima = value,
@@ -81,4 +93,15 @@ impl Timer {
             _ => panic!("Invalid timer register address"),
         }
     }
-}
\ No newline at end of file
+
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+    }
+
+    // Once per TIMA overflow
+    fn take_interrupts(&mut self) -> u8 {
+        if std::mem::replace(&mut self.interrupt, false) { TIMER_INTERRUPT } else { 0 }
+    }
+}
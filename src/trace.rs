@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+// Which instructions end up in the trace log
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<u16>>,
+    pub bank: Option<u16>,  // only instructions executed from this ROM bank
+    pub skip: u64,          // instructions to execute before logging starts
+    pub limit: Option<u64>, // maximum number of lines to write
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: u16, bank: Option<u16>) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&pc) {
+                return false;
+            }
+        }
+        match self.bank {
+            Some(wanted) => bank == Some(wanted),
+            None => true,
+        }
+    }
+}
+
+// Writes one line per executed instruction in the format used by
+// Gameboy Doctor, so logs can be diffed against other emulators
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    filter: TraceFilter,
+    executed: u64,
+    written: u64,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, filter: TraceFilter) -> Self {
+        Tracer { writer, filter, executed: 0, written: 0 }
+    }
+
+    pub fn create(path: &str, filter: TraceFilter) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file)), filter))
+    }
+
+    // Called once per instruction before it executes; true if it should be logged
+    // `bank` is the ROM bank PC is in, None outside ROM
+    pub fn should_log(&mut self, pc: u16, bank: Option<u16>) -> bool {
+        self.executed += 1;
+        if self.executed <= self.filter.skip {
+            return false;
+        }
+        if self.filter.limit.is_some_and(|limit| self.written >= limit) {
+            return false;
+        }
+        self.filter.matches(pc, bank)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.written += 1;
+        writeln!(self.writer, "{}", line)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.filter.limit.is_some_and(|limit| self.written >= limit)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+// Parse "0150-01FF" or a single address "0150" into an inclusive PC range
+pub fn parse_pc_range(text: &str) -> Option<RangeInclusive<u16>> {
+    let parse = |value: &str| {
+        let value = value.trim().trim_start_matches("0x").trim_start_matches('$');
+        u16::from_str_radix(value, 16).ok()
+    };
+    match text.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            (start <= end).then_some(start..=end)
+        }
+        None => parse(text).map(|addr| addr..=addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatBus;
+    use crate::cpu::{Registers, CPU};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A log the test can still read after handing it to a Tracer
+    #[derive(Clone, Default)]
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedLog {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    // A CPU in the DMG post-boot state at `pc`, logging to the returned log
+    fn traced_cpu(pc: u16, filter: TraceFilter) -> (CPU, SharedLog) {
+        let log = SharedLog::default();
+        let mut registers = Registers { sp: 0xFFFE, pc, ..Registers::default() };
+        registers.set_af(0x01B0);
+        registers.set_bc(0x0013);
+        registers.set_de(0x00D8);
+        registers.set_hl(0x014D);
+        let mut cpu = CPU::new();
+        cpu.set_registers(registers);
+        cpu.set_tracer(Some(Tracer::new(Box::new(log.clone()), filter)));
+        (cpu, log)
+    }
+
+    fn tracer(filter: TraceFilter) -> Tracer {
+        Tracer::new(Box::new(io::sink()), filter)
+    }
+
+    #[test]
+    fn writes_gameboy_doctor_lines() {
+        let mut bus = FlatBus::new();
+        bus.memory[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let (mut cpu, log) = traced_cpu(0x0100, TraceFilter::default());
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(log.lines(), [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+        ]);
+    }
+
+    #[test]
+    fn pc_range_includes_and_excludes() {
+        let mut tracer = tracer(TraceFilter { pc_range: Some(0x0150..=0x01FF), ..TraceFilter::default() });
+        assert!(!tracer.should_log(0x014F, Some(0)));
+        assert!(tracer.should_log(0x0150, Some(0)));
+        assert!(tracer.should_log(0x01FF, Some(0)));
+        assert!(!tracer.should_log(0x0200, Some(0)));
+    }
+
+    #[test]
+    fn bank_filter_only_logs_that_bank() {
+        let mut tracer = tracer(TraceFilter { bank: Some(1), ..TraceFilter::default() });
+        assert!(tracer.should_log(0x4000, Some(1)));
+        assert!(!tracer.should_log(0x4000, Some(2)));
+        assert!(!tracer.should_log(0xC000, None));
+
+        // The CPU reports bank 0 below 0x4000 and the mapped bank above it
+        for (bank, first_pc) in [(0, "PC:3FFE"), (1, "PC:4000")] {
+            let mut bus = FlatBus::new();
+            let (mut cpu, log) = traced_cpu(0x3FFE, TraceFilter { bank: Some(bank), ..TraceFilter::default() });
+            for _ in 0..4 {
+                cpu.step(&mut bus);
+            }
+            let lines = log.lines();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].contains(first_pc), "{}", lines[0]);
+        }
+    }
+
+    #[test]
+    fn skips_then_stops_at_the_limit() {
+        let mut tracer = tracer(TraceFilter { skip: 2, limit: Some(2), ..TraceFilter::default() });
+        let mut logged = Vec::new();
+        for pc in 0..6 {
+            if tracer.should_log(pc, Some(0)) {
+                tracer.write_line("").unwrap();
+                logged.push(pc);
+            }
+            assert_eq!(tracer.is_finished(), logged.len() == 2);
+        }
+        assert_eq!(logged, [2, 3]);
+    }
+
+    #[test]
+    fn parses_pc_ranges() {
+        assert_eq!(parse_pc_range("0150-01FF"), Some(0x0150..=0x01FF));
+        assert_eq!(parse_pc_range("$c000 - 0xC0ff"), Some(0xC000..=0xC0FF));
+        assert_eq!(parse_pc_range("0150"), Some(0x0150..=0x0150));
+        assert_eq!(parse_pc_range("01FF-0150"), None);
+        assert_eq!(parse_pc_range("01G0"), None);
+        assert_eq!(parse_pc_range("0150-"), None);
+        assert_eq!(parse_pc_range("10000"), None);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::disasm::{self, ByteSource, Instruction};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+
+const FLAG_NAMES: [(u8, &str); 4] = [(0x80, "Z"), (0x40, "N"), (0x20, "H"), (0x10, "C")];
+
+// One parsed line of a Gameboy Doctor trace
+struct TraceLine {
+    text: String,
+    fields: Vec<(String, String)>,
+}
+
+impl TraceLine {
+    fn parse(text: String) -> Self {
+        let fields = text.split_whitespace()
+            .filter_map(|field| field.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        TraceLine { text, fields }
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.as_str())
+    }
+
+    // The instruction about to run, decoded from the PC and PCMEM fields
+    fn instruction(&self) -> Option<Instruction> {
+        let pc = u16::from_str_radix(self.field("PC")?, 16).ok()?;
+        let mut bytes = [0xFF; 4];
+        for (byte, text) in bytes.iter_mut().zip(self.field("PCMEM")?.split(',')) {
+            *byte = u8::from_str_radix(text, 16).ok()?;
+        }
+        Some(disasm::decode(&MemoryWindow { pc, bytes }, pc))
+    }
+}
+
+// The four bytes at PC recorded in a trace line
+struct MemoryWindow {
+    pc: u16,
+    bytes: [u8; 4],
+}
+
+impl ByteSource for MemoryWindow {
+    fn byte_at(&self, addr: u16) -> u8 {
+        self.bytes.get(addr.wrapping_sub(self.pc) as usize).copied().unwrap_or(0xFF)
+    }
+}
+
+pub struct FieldDifference {
+    pub name: String,
+    pub ours: String,
+    pub reference: String,
+}
+
+impl fmt::Display for FieldDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} (expected {})", self.name, self.ours, self.reference)?;
+        if self.name != "F" {
+            return Ok(());
+        }
+        let (Ok(ours), Ok(reference)) = (u8::from_str_radix(&self.ours, 16), u8::from_str_radix(&self.reference, 16)) else {
+            return Ok(());
+        };
+        let flags: Vec<String> = FLAG_NAMES.iter()
+            .filter(|&&(mask, _)| (ours ^ reference) & mask != 0)
+            .map(|&(mask, name)| format!("{}={} expected {}", name, (ours & mask != 0) as u8, (reference & mask != 0) as u8))
+            .collect();
+        write!(f, " [{}]", flags.join(", "))
+    }
+}
+
+// The first point where two traces disagree
+pub struct Divergence {
+    pub line: usize,                        // 1-based line number
+    pub context: Vec<String>,               // matching lines leading up to it
+    pub ours: Option<String>,               // None if our trace ended early
+    pub reference: Option<String>,          // None if the reference ended early
+    pub differences: Vec<FieldDifference>,
+    pub previous: Option<Instruction>,      // last instruction both agreed on
+    pub current: Option<Instruction>,       // instruction at the diverging line
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Traces diverge at line {}", self.line)?;
+        let first_context = self.line - self.context.len();
+        for (i, line) in self.context.iter().enumerate() {
+            writeln!(f, "  {:>8}   {}", first_context + i, line)?;
+        }
+        writeln!(f, "  {:>8} < {}", self.line, self.ours.as_deref().unwrap_or("<end of trace>"))?;
+        writeln!(f, "  {:>8} > {}", self.line, self.reference.as_deref().unwrap_or("<end of trace>"))?;
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        if let Some(instruction) = &self.previous {
+            writeln!(f, "Last instruction executed: {:04X}: {}  ({})", instruction.address, instruction, hex(instruction.bytes()))?;
+        }
+        if let Some(instruction) = &self.current {
+            writeln!(f, "Next instruction: {:04X}: {}  ({})", instruction.address, instruction, hex(instruction.bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+// Stream both traces and return the first divergence, keeping `context`
+// matching lines before it
+pub fn diff<A: BufRead, B: BufRead>(ours: A, reference: B, context: usize) -> io::Result<Option<Divergence>> {
+    let mut ours = ours.lines();
+    let mut reference = reference.lines();
+    let mut history: VecDeque<String> = VecDeque::with_capacity(context + 1);
+    let mut previous: Option<TraceLine> = None;
+    let mut line = 0;
+
+    loop {
+        line += 1;
+        let (our_line, reference_line) = match (ours.next().transpose()?, reference.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (Some(a), Some(b)) if a.trim() == b.trim() => {
+                if context > 0 {
+                    if history.len() == context {
+                        history.pop_front();
+                    }
+                    history.push_back(a.clone());
+                }
+                previous = Some(TraceLine::parse(a));
+                continue;
+            }
+            (a, b) => (a.map(TraceLine::parse), b.map(TraceLine::parse)),
+        };
+
+        let mut differences = Vec::new();
+        if let (Some(a), Some(b)) = (&our_line, &reference_line) {
+            for (name, expected) in &b.fields {
+                let actual = a.field(name).unwrap_or("--");
+                if actual != expected {
+                    differences.push(FieldDifference { name: name.clone(), ours: actual.to_string(), reference: expected.clone() });
+                }
+            }
+        }
+
+        return Ok(Some(Divergence {
+            line,
+            context: history.into_iter().collect(),
+            previous: previous.as_ref().and_then(TraceLine::instruction),
+            current: reference_line.as_ref().or(our_line.as_ref()).and_then(TraceLine::instruction),
+            ours: our_line.map(|l| l.text),
+            reference: reference_line.map(|l| l.text),
+            differences,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE_1: &str = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01";
+    const LINE_2: &str = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,CE";
+    const LINE_3: &str = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:FE,11,20,03";
+    const LINE_4: &str = "A:01 F:50 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:20,03,AF,18";
+
+    fn run(ours: &[&str], reference: &[&str], context: usize) -> Option<Divergence> {
+        diff(ours.join("\n").as_bytes(), reference.join("\n").as_bytes(), context).unwrap()
+    }
+
+    #[test]
+    fn identical_traces_match() {
+        assert!(run(&[LINE_1, LINE_2], &[LINE_1, LINE_2], 10).is_none());
+    }
+
+    #[test]
+    fn reports_the_first_divergence() {
+        let ours = LINE_4.replace("F:50", "F:C0");
+        let divergence = run(&[LINE_1, LINE_2, LINE_3, &ours], &[LINE_1, LINE_2, LINE_3, LINE_4], 2).unwrap();
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.context, [LINE_2, LINE_3]);
+        assert_eq!(divergence.differences.len(), 1);
+        assert_eq!(divergence.previous.unwrap().to_string(), "cp a, $11");
+        assert_eq!(divergence.current.unwrap().to_string(), "jr nz, $0157");
+    }
+
+    #[test]
+    fn breaks_down_differing_flags() {
+        let ours = LINE_4.replace("F:50", "F:C0");
+        let divergence = run(&[&ours], &[LINE_4], 0).unwrap();
+        let difference = &divergence.differences[0];
+        assert_eq!((difference.name.as_str(), difference.ours.as_str()), ("F", "C0"));
+        assert_eq!(difference.to_string(), "F: C0 (expected 50) [Z=1 expected 0, C=0 expected 1]");
+    }
+
+    #[test]
+    fn reports_a_trace_ending_early() {
+        let divergence = run(&[LINE_1], &[LINE_1, LINE_2], 10).unwrap();
+        assert_eq!(divergence.line, 2);
+        assert!(divergence.ours.is_none());
+        assert_eq!(divergence.reference.as_deref(), Some(LINE_2));
+        assert!(divergence.differences.is_empty());
+        assert_eq!(divergence.current.unwrap().to_string(), "jp $0150");
+    }
+}
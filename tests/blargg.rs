@@ -0,0 +1,84 @@
+// Blargg's test suites, run headlessly with results read from the serial
+// port. The ROMs are not distributed with the emulator: put them under
+// tests/roms/blargg (or point RUSTY_BOY_TEST_ROMS elsewhere) and run
+// `cargo test -- --ignored`.
+
+mod common;
+
+use rusty_boy::harness::{self, Outcome, TestResult};
+use std::path::PathBuf;
+
+fn run_suite(path: &str, cycle_budget: u64) {
+    let path = harness::test_rom_dir().join("blargg").join(path);
+    assert!(path.exists(), "{} not found", path.display());
+
+    let roms = if path.is_dir() {
+        harness::find_roms(&path).unwrap()
+    } else {
+        vec![path]
+    };
+    let results: Vec<TestResult> = roms.iter()
+        .map(|rom| harness::run_serial_test(rom, cycle_budget).unwrap())
+        .collect();
+
+    let report = harness::report(&results);
+    assert!(results.iter().all(TestResult::passed), "{}", report);
+    println!("{}", report);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs"]
+fn cpu_instrs() {
+    run_suite("cpu_instrs/individual", 300_000_000);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs"]
+fn instr_timing() {
+    run_suite("instr_timing/instr_timing.gb", 100_000_000);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs"]
+fn mem_timing() {
+    run_suite("mem_timing/individual", 100_000_000);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs"]
+fn halt_bug() {
+    run_suite("halt_bug.gb", 100_000_000);
+}
+
+// Print `message` through the serial port the way Blargg's ROMs do, then
+// loop forever
+fn serial_rom(name: &str, message: &str) -> PathBuf {
+    let mut program = vec![
+        0x21, 0x62, 0x01,   // 0150: ld hl, $0162
+        0x2A,               // 0153: ld a, (hl+)
+        0xFE, 0x00,         // 0154: cp $00
+        0x28, 0x08,         // 0156: jr z, $0160
+        0xE0, 0x01,         // 0158: ldh ($01), a
+        0x3E, 0x81,         // 015A: ld a, $81
+        0xE0, 0x02,         // 015C: ldh ($02), a
+        0x18, 0xF3,         // 015E: jr $0153
+        0x18, 0xFE,         // 0160: jr $0160
+    ];
+    program.extend_from_slice(message.as_bytes());
+    program.push(0);
+    common::write_rom(name, &program)
+}
+
+#[test]
+fn serial_harness() {
+    let passed = harness::run_serial_test(&serial_rom("serial_passed", "cpu_instrs\n\nPassed\n"), 1_000_000).unwrap();
+    assert_eq!(passed.outcome, Outcome::Passed);
+    assert_eq!(passed.output, "cpu_instrs\n\nPassed");
+
+    let failed = harness::run_serial_test(&serial_rom("serial_failed", "01:ok 02:03 Failed\n"), 10_000_000).unwrap();
+    assert_eq!(failed.outcome, Outcome::Failed);
+
+    let silent = harness::run_serial_test(&serial_rom("serial_silent", ""), 100_000).unwrap();
+    assert_eq!(silent.outcome, Outcome::Timeout);
+    assert!(silent.output.is_empty());
+}
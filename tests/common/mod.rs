@@ -0,0 +1,16 @@
+// Hand-assembled cartridges for the tests that run without downloaded ROMs
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A 32 KiB ROM-only DMG cartridge that jumps from the entry point to
+// `program` at 0x150, saved to the target directory for the harness to load
+pub fn write_rom(name: &str, program: &[u8]) -> PathBuf {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // nop; jp $0150
+    rom[0x150..0x150 + program.len()].copy_from_slice(program);
+
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.gb", name));
+    fs::write(&path, rom).unwrap();
+    path
+}
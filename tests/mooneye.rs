@@ -0,0 +1,101 @@
+// Mooneye acceptance tests, judged by the register signature at the LD B,B
+// breakpoint. Put the mooneye-test-suite build under tests/roms/mooneye (or
+// point RUSTY_BOY_TEST_ROMS elsewhere) and run `cargo test -- --ignored`.
+
+mod common;
+
+use rusty_boy::harness::{self, Outcome, TestResult};
+use std::path::PathBuf;
+
+const CYCLE_BUDGET: u64 = 120_000_000;
+
+// Run every DMG test in `dir`, optionally including subdirectories
+fn run_acceptance(dir: &str, recursive: bool) {
+    let dir = harness::test_rom_dir().join("mooneye").join("acceptance").join(dir);
+    assert!(dir.exists(), "{} not found", dir.display());
+
+    let results: Vec<TestResult> = harness::find_roms(&dir).unwrap().iter()
+        .filter(|rom| recursive || rom.parent() == Some(dir.as_path()))
+        .filter(|rom| harness::runs_on_dmg(rom))
+        .map(|rom| harness::run_mooneye_test(rom, CYCLE_BUDGET).unwrap())
+        .collect();
+
+    let report = harness::report(&results);
+    assert!(results.iter().all(TestResult::passed), "{}", report);
+    println!("{}", report);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn acceptance() {
+    run_acceptance("", false);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn bits() {
+    run_acceptance("bits", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn instr() {
+    run_acceptance("instr", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn interrupts() {
+    run_acceptance("interrupts", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn oam_dma() {
+    run_acceptance("oam_dma", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn ppu() {
+    run_acceptance("ppu", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn serial() {
+    run_acceptance("serial", true);
+}
+
+#[test]
+#[ignore = "needs the Mooneye test suite"]
+fn timer() {
+    run_acceptance("timer", true);
+}
+
+// Load `registers` into B, C, D, E, H and L and hit the LD B,B breakpoint
+fn signature_rom(name: &str, registers: [u8; 6]) -> PathBuf {
+    let mut program = Vec::new();
+    for (opcode, value) in [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E].into_iter().zip(registers) {
+        program.extend_from_slice(&[opcode, value]);    // ld r, value
+    }
+    program.extend_from_slice(&[
+        0x40,           // ld b, b
+        0x18, 0xFE,     // jr -2
+    ]);
+    common::write_rom(name, &program)
+}
+
+#[test]
+fn breakpoint_signature() {
+    let passed = harness::run_mooneye_test(&signature_rom("mooneye_passed", [3, 5, 8, 13, 21, 34]), 100_000).unwrap();
+    assert_eq!(passed.outcome, Outcome::Passed, "{}", passed.output);
+
+    let failed = harness::run_mooneye_test(&signature_rom("mooneye_failed", [0x42; 6]), 100_000).unwrap();
+    assert_eq!(failed.outcome, Outcome::Failed);
+    assert!(failed.output.starts_with("Test reported failure"), "{}", failed.output);
+
+    let wrong = harness::run_mooneye_test(&signature_rom("mooneye_wrong", [3, 5, 8, 13, 21, 33]), 100_000).unwrap();
+    assert_eq!(wrong.outcome, Outcome::Failed);
+    assert!(wrong.output.contains("L:21"), "{}", wrong.output);
+}
@@ -0,0 +1,248 @@
+// PPU regression tests comparing the screen against reference screenshots
+// pixel for pixel. The ROMs are not distributed with the emulator: put them
+// under tests/roms (or point RUSTY_BOY_TEST_ROMS elsewhere) and run
+// `cargo test -- --ignored`:
+//
+//   dmg-acid2/dmg-acid2.gb       with dmg-acid2/reference-dmg.png
+//   cgb-acid2/cgb-acid2.gbc      with cgb-acid2/reference.png
+//   mealybug/*.gb                with mealybug/expected/DMG-blob/*.png
+//
+// The mealybug tests change registers mid-line, so they need the pixel
+// FIFO renderer; the acid2 tests are run with both renderers.
+//
+// On a mismatch the captured screen and a diff image are written to the
+// target directory.
+
+mod common;
+
+use rusty_boy::color::DmgPalette;
+use rusty_boy::harness::{self, Screenshot};
+use rusty_boy::ppu::Renderer;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+const FRAME_LIMIT: u32 = 600;
+
+// Reference image as RGB pixels
+fn load_png(path: &Path) -> (usize, usize, Vec<[u8; 3]>) {
+    let mut decoder = png::Decoder::new(File::open(path).unwrap());
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().unwrap();
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).unwrap();
+    let data = &buffer[..info.buffer_size()];
+
+    let pixels = match info.color_type {
+        png::ColorType::Grayscale => data.iter().map(|&v| [v, v, v]).collect(),
+        png::ColorType::GrayscaleAlpha => data.chunks(2).map(|p| [p[0], p[0], p[0]]).collect(),
+        png::ColorType::Rgb => data.chunks(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        png::ColorType::Rgba => data.chunks(4).map(|p| [p[0], p[1], p[2]]).collect(),
+        png::ColorType::Indexed => unreachable!("expanded by the decoder"),
+    };
+    (info.width as usize, info.height as usize, pixels)
+}
+
+fn save_png(path: &Path, width: usize, height: usize, pixels: &[[u8; 3]]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+    encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
+
+// Matching pixels dimmed, differing ones in red
+fn diff_image(actual: &[[u8; 3]], expected: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    actual.iter().zip(expected)
+        .map(|(a, e)| {
+            if a == e {
+                let v = (e[0] as u16 + e[1] as u16 + e[2] as u16) / 3 / 4 + 0xC0;
+                [v as u8; 3]
+            } else {
+                [0xFF, 0x00, 0x00]
+            }
+        })
+        .collect()
+}
+
+fn output_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Compare one ROM's final screen with its reference, describing any mismatch
+fn check_screenshot(rom: &Path, reference: &Path, renderer: Renderer) -> Result<(), String> {
+    let capture = panic::catch_unwind(AssertUnwindSafe(|| harness::run_screenshot_test(rom, FRAME_LIMIT, renderer)));
+    let screenshot: Screenshot = match capture {
+        Ok(result) => result.map_err(|error| format!("{}: {}", rom.display(), error))?,
+        Err(_) => return Err(format!("{}: emulator panicked", rom.display())),
+    };
+
+    let (width, height, expected) = load_png(reference);
+    if (width, height) != (screenshot.width, screenshot.height) {
+        return Err(format!(
+            "{}: reference is {}x{}, screen is {}x{}",
+            rom.display(), width, height, screenshot.width, screenshot.height,
+        ));
+    }
+
+    let mismatches = screenshot.pixels.iter().zip(&expected).filter(|(a, e)| a != e).count();
+    if mismatches == 0 {
+        return Ok(());
+    }
+
+    let stem = format!("{}-{:?}", rom.file_stem().unwrap().to_string_lossy(), renderer).to_lowercase();
+    let dir = output_dir();
+    let actual_path = dir.join(format!("{}-actual.png", stem));
+    let diff_path = dir.join(format!("{}-diff.png", stem));
+    save_png(&actual_path, width, height, &screenshot.pixels);
+    save_png(&diff_path, width, height, &diff_image(&screenshot.pixels, &expected));
+
+    let first = screenshot.pixels.iter().zip(&expected).position(|(a, e)| a != e).unwrap();
+    Err(format!(
+        "{}: {} pixels differ, first at ({}, {}){}\n  screen: {}\n  diff:   {}",
+        rom.display(),
+        mismatches,
+        first % width,
+        first / width,
+        if screenshot.breakpoint { "" } else { " (never reached LD B,B)" },
+        actual_path.display(),
+        diff_path.display(),
+    ))
+}
+
+fn run_single(rom: &str, reference: &str, renderer: Renderer) {
+    let dir = harness::test_rom_dir();
+    let (rom, reference) = (dir.join(rom), dir.join(reference));
+    assert!(rom.exists(), "{} not found", rom.display());
+    assert!(reference.exists(), "{} not found", reference.display());
+    if let Err(message) = check_screenshot(&rom, &reference, renderer) {
+        panic!("{}", message);
+    }
+}
+
+// Every ROM in `roms` whose name contains `filter` and that has a reference
+// of the same name in `expected`
+fn run_suite(roms: &str, expected: &str, filter: &str, renderer: Renderer) {
+    let dir = harness::test_rom_dir();
+    let (roms, expected) = (dir.join(roms), dir.join(expected));
+    assert!(roms.exists(), "{} not found", roms.display());
+    assert!(expected.exists(), "{} not found", expected.display());
+
+    let mut checked = 0;
+    let mut failures = Vec::new();
+    for rom in harness::find_roms(&roms).unwrap() {
+        if !rom.file_stem().unwrap().to_string_lossy().contains(filter) {
+            continue;
+        }
+        let reference = expected.join(rom.file_stem().unwrap()).with_extension("png");
+        if !reference.exists() {
+            continue;
+        }
+        checked += 1;
+        if let Err(message) = check_screenshot(&rom, &reference, renderer) {
+            failures.push(message);
+        }
+    }
+
+    assert!(failures.is_empty(), "{}\n{}/{} screenshots differ", failures.join("\n"), failures.len(), checked);
+    println!("{} screenshots match", checked);
+}
+
+#[test]
+#[ignore = "needs the acid2 test ROMs"]
+fn dmg_acid2() {
+    run_single("dmg-acid2/dmg-acid2.gb", "dmg-acid2/reference-dmg.png", Renderer::Scanline);
+}
+
+#[test]
+#[ignore = "needs the acid2 test ROMs"]
+fn dmg_acid2_fifo() {
+    run_single("dmg-acid2/dmg-acid2.gb", "dmg-acid2/reference-dmg.png", Renderer::Fifo);
+}
+
+#[test]
+#[ignore = "needs the acid2 test ROMs"]
+fn cgb_acid2() {
+    run_single("cgb-acid2/cgb-acid2.gbc", "cgb-acid2/reference.png", Renderer::Scanline);
+}
+
+#[test]
+#[ignore = "needs the acid2 test ROMs"]
+fn cgb_acid2_fifo() {
+    run_single("cgb-acid2/cgb-acid2.gbc", "cgb-acid2/reference.png", Renderer::Fifo);
+}
+
+#[test]
+#[ignore = "needs the Mealybug Tearoom tests"]
+fn mealybug() {
+    run_suite("mealybug", "mealybug/expected/DMG-blob", "", Renderer::Fifo);
+}
+
+// Just the m3_wx_* tests, which move the window horizontally during mode 3
+#[test]
+#[ignore = "needs the Mealybug Tearoom tests"]
+fn mealybug_window() {
+    run_suite("mealybug", "mealybug/expected/DMG-blob", "m3_wx_", Renderer::Fifo);
+}
+
+// Fill tile 0, which the whole background map points at, with columns of
+// colors 1, 3, 2 and 0. The frame after the LCD is switched on stays
+// blank, so wait for the next one to start before LD B,B.
+fn stripes_rom() -> PathBuf {
+    common::write_rom("stripes", &[
+        0x3E, 0x00,         // 0150: ld a, $00
+        0xE0, 0x40,         // 0152: ldh ($40), a       LCD off
+        0x21, 0x00, 0x80,   // 0154: ld hl, $8000
+        0x06, 0x08,         // 0157: ld b, $08
+        0x3E, 0xF0,         // 0159: ld a, $F0
+        0x77,               // 015B: ld (hl), a
+        0x23,               // 015C: inc hl
+        0x3E, 0x3C,         // 015D: ld a, $3C
+        0x77,               // 015F: ld (hl), a
+        0x23,               // 0160: inc hl
+        0x05,               // 0161: dec b
+        0xC2, 0x59, 0x01,   // 0162: jp nz, $0159
+        0x3E, 0xE4,         // 0165: ld a, $E4
+        0xE0, 0x47,         // 0167: ldh ($47), a       BGP
+        0x3E, 0x91,         // 0169: ld a, $91
+        0xE0, 0x40,         // 016B: ldh ($40), a       LCD on
+        0xF0, 0x44,         // 016D: ldh a, ($44)       LY
+        0xFE, 0x90,         // 016F: cp $90
+        0xC2, 0x6D, 0x01,   // 0171: jp nz, $016D
+        0xF0, 0x44,         // 0174: ldh a, ($44)
+        0xFE, 0x00,         // 0176: cp $00
+        0xC2, 0x74, 0x01,   // 0178: jp nz, $0174
+        0x40,               // 017B: ld b, b
+        0x18, 0xFE,         // 017C: jr $017C
+    ])
+}
+
+#[test]
+fn screenshot_harness() {
+    let rom = stripes_rom();
+    let shades = DmgPalette::default().0.map(|rgb| {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        [r, g, b]
+    });
+    let row: Vec<[u8; 3]> = (0..160).map(|x| shades[[1, 1, 3, 3, 2, 2, 0, 0][x % 8]]).collect();
+    let expected = row.repeat(144);
+
+    let reference = output_dir().join("stripes-reference.png");
+    save_png(&reference, 160, 144, &expected);
+    for renderer in [Renderer::Scanline, Renderer::Fifo] {
+        if let Err(message) = check_screenshot(&rom, &reference, renderer) {
+            panic!("{}", message);
+        }
+    }
+
+    // A reference that differs by one pixel must be caught
+    let mut wrong = expected.clone();
+    wrong[160 * 100 + 42] = [0xFF, 0x00, 0xFF];
+    save_png(&reference, 160, 144, &wrong);
+    let message = check_screenshot(&rom, &reference, Renderer::Scanline).unwrap_err();
+    assert!(message.contains("1 pixels differ, first at (42, 100)"), "{}", message);
+}
@@ -0,0 +1,251 @@
+// Per-opcode conformance against the SingleStepTests SM83 suite. Every
+// case runs one instruction on a flat 64 KiB bus and checks registers,
+// memory and each bus access. The JSON files are not distributed with the
+// emulator: put the suite's v1 directory at tests/roms/sm83/v1 (or point
+// RUSTY_BOY_TEST_ROMS elsewhere) and run `cargo test -- --ignored`.
+//
+// The suite models the SM83 fetch/execute overlap: PC starts one past the
+// opcode, which has already been fetched, and the last M-cycle of every
+// instruction fetches the next opcode. Our CPU fetches at the start of
+// step() instead, so it starts at PC - 1 and the trailing fetch is done by
+// hand before comparing.
+
+use rusty_boy::bus::{AccessKind, Bus, BusAccess, FlatBus, RecordingBus};
+use rusty_boy::cpu::{Registers, CPU};
+use rusty_boy::harness;
+use serde_json::{json, Value};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+// Failing cases printed in full for each opcode
+const FAILURES_SHOWN: usize = 3;
+
+fn registers(state: &Value) -> Registers {
+    let byte = |name: &str| state[name].as_u64().unwrap() as u8;
+    let word = |name: &str| state[name].as_u64().unwrap() as u16;
+    Registers {
+        a: byte("a"),
+        f: byte("f"),
+        b: byte("b"),
+        c: byte("c"),
+        d: byte("d"),
+        e: byte("e"),
+        h: byte("h"),
+        l: byte("l"),
+        sp: word("sp"),
+        pc: word("pc"),
+        ime: byte("ime") != 0,
+        halt: false,
+    }
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"].as_array().unwrap().iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect()
+}
+
+// Bus activity of each M-cycle. Idle cycles are null or have neither the
+// read nor the write pin set.
+fn bus_cycles(cycles: &Value) -> Vec<BusAccess> {
+    const IDLE: BusAccess = BusAccess { addr: 0, value: 0, kind: AccessKind::Idle };
+    cycles.as_array().unwrap().iter()
+        .map(|cycle| {
+            let Some(cycle) = cycle.as_array() else {
+                return IDLE;
+            };
+            let pins = cycle.get(2).and_then(Value::as_str).unwrap_or("");
+            let kind = if pins.contains('r') {
+                AccessKind::Read
+            } else if pins.contains('w') {
+                AccessKind::Write
+            } else {
+                return IDLE;
+            };
+            let addr = cycle[0].as_u64().unwrap() as u16;
+            let value = cycle[1].as_u64().unwrap() as u8;
+            BusAccess { addr, value, kind }
+        })
+        .collect()
+}
+
+fn describe(access: Option<&BusAccess>) -> String {
+    match access {
+        Some(access) if access.kind == AccessKind::Idle => "Idle".to_string(),
+        Some(access) => format!("{:?} {:04X} = {:02X}", access.kind, access.addr, access.value),
+        None => "--".to_string(),
+    }
+}
+
+// Run one test case, returning what went wrong
+fn run_case(case: &Value) -> Vec<String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut memory = FlatBus::new();
+    for (addr, value) in ram(initial) {
+        memory.memory[addr as usize] = value;
+    }
+    memory.memory[0xFFFF] = initial["ie"].as_u64().unwrap_or(0) as u8;
+    let mut bus = RecordingBus::new(memory);
+
+    let mut start = registers(initial);
+    start.pc = start.pc.wrapping_sub(1);
+    let mut cpu = CPU::new();
+    cpu.set_registers(start);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| cpu.step(&mut bus)));
+    let cycles = match result {
+        Ok(cycles) => cycles,
+        Err(error) => {
+            let message = error.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| error.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            return vec![format!("panicked: {}", message)];
+        }
+    };
+    let pc = cpu.registers().pc;
+    bus.read_byte(pc);
+
+    let mut problems = Vec::new();
+    let ours = Registers { pc: pc.wrapping_add(1), ..cpu.registers() };
+    let theirs = registers(expected);
+    let pairs = [
+        ("A", ours.a as u16, theirs.a as u16, 2),
+        ("F", ours.f as u16, theirs.f as u16, 2),
+        ("B", ours.b as u16, theirs.b as u16, 2),
+        ("C", ours.c as u16, theirs.c as u16, 2),
+        ("D", ours.d as u16, theirs.d as u16, 2),
+        ("E", ours.e as u16, theirs.e as u16, 2),
+        ("H", ours.h as u16, theirs.h as u16, 2),
+        ("L", ours.l as u16, theirs.l as u16, 2),
+        ("SP", ours.sp, theirs.sp, 4),
+        ("PC", ours.pc, theirs.pc, 4),
+        ("IME", ours.ime as u16, theirs.ime as u16, 1),
+    ];
+    for (name, actual, wanted, width) in pairs {
+        if actual != wanted {
+            problems.push(format!("{}: {:0w$X} (expected {:0w$X})", name, actual, wanted, w = width));
+        }
+    }
+    for (addr, wanted) in ram(expected) {
+        let actual = bus.peek(addr);
+        if actual != wanted {
+            problems.push(format!("[{:04X}]: {:02X} (expected {:02X})", addr, actual, wanted));
+        }
+    }
+
+    let m_cycles = case["cycles"].as_array().map_or(0, Vec::len) as u32;
+    if cycles != m_cycles * 4 {
+        problems.push(format!("cycles: {} (expected {})", cycles, m_cycles * 4));
+    }
+
+    // The opcode fetch belongs to the previous instruction on hardware
+    let ours = &bus.accesses[1..];
+    let theirs = bus_cycles(&case["cycles"]);
+    if ours != theirs.as_slice() {
+        problems.push("bus cycles differ:".to_string());
+        for i in 0..ours.len().max(theirs.len()) {
+            let marker = if ours.get(i) == theirs.get(i) { ' ' } else { '!' };
+            problems.push(format!(
+                "  {} {:<22} expected {}",
+                marker,
+                describe(ours.get(i)),
+                describe(theirs.get(i)),
+            ));
+        }
+    }
+    problems
+}
+
+// Run every case in one opcode's file, returning a report if any failed
+fn run_file(path: &Path) -> Option<String> {
+    let cases: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    let cases = cases.as_array().unwrap();
+
+    let mut report = String::new();
+    let mut failed = 0;
+    for case in cases {
+        let problems = run_case(case);
+        if problems.is_empty() {
+            continue;
+        }
+        failed += 1;
+        if failed <= FAILURES_SHOWN {
+            report.push_str(&format!("  {}\n", case["name"].as_str().unwrap_or("?")));
+            for problem in problems {
+                report.push_str(&format!("    {}\n", problem));
+            }
+        }
+    }
+
+    if failed == 0 {
+        return None;
+    }
+    let name = path.file_stem().unwrap().to_string_lossy();
+    Some(format!("{}: {}/{} cases failed\n{}", name, failed, cases.len(), report))
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests SM83 suite"]
+fn sm83_single_step() {
+    let dir = harness::test_rom_dir().join("sm83").join("v1");
+    assert!(dir.exists(), "{} not found", dir.display());
+
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+
+    let failures: Vec<String> = files.iter().filter_map(|path| run_file(path)).collect();
+    assert!(
+        failures.is_empty(),
+        "{}\n{}/{} opcodes failed",
+        failures.join("\n"),
+        failures.len(),
+        files.len(),
+    );
+}
+
+// Cases in the suite's format, checking that idle cycles are compared too
+#[test]
+fn per_cycle_comparison() {
+    // inc bc: one idle cycle before the next opcode fetch
+    let inc_bc = json!({
+        "name": "03 0000",
+        "initial": {
+            "a": 0, "b": 0x12, "c": 0xFF, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+            "pc": 0xC001, "sp": 0xFFFE, "ime": 0, "ram": [[0xC000, 0x03], [0xC001, 0x00]],
+        },
+        "final": {
+            "a": 0, "b": 0x13, "c": 0x00, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+            "pc": 0xC002, "sp": 0xFFFE, "ime": 0, "ram": [[0xC000, 0x03], [0xC001, 0x00]],
+        },
+        "cycles": [null, [0xC001, 0x00, "r-m"]],
+    });
+    assert_eq!(run_case(&inc_bc), Vec::<String>::new());
+
+    // jp $1234: two operand reads, then an idle cycle
+    let mut jp = json!({
+        "name": "c3 0000",
+        "initial": {
+            "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+            "pc": 0xC001, "sp": 0xFFFE, "ime": 0,
+            "ram": [[0xC000, 0xC3], [0xC001, 0x34], [0xC002, 0x12], [0x1234, 0x00]],
+        },
+        "final": {
+            "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+            "pc": 0x1235, "sp": 0xFFFE, "ime": 0,
+            "ram": [[0xC000, 0xC3], [0xC001, 0x34], [0xC002, 0x12], [0x1234, 0x00]],
+        },
+        "cycles": [[0xC001, 0x34, "r-m"], [0xC002, 0x12, "r-m"], [0xC002, null, "---"], [0x1234, 0x00, "r-m"]],
+    });
+    assert_eq!(run_case(&jp), Vec::<String>::new());
+
+    // The same accesses with the idle cycle in the wrong place must fail
+    jp["cycles"] = json!([[0xC001, 0x34, "r-m"], null, [0xC002, 0x12, "r-m"], [0x1234, 0x00, "r-m"]]);
+    let problems = run_case(&jp);
+    assert_eq!(problems.first().map(String::as_str), Some("bus cycles differ:"), "{:?}", problems);
+}